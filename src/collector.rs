@@ -0,0 +1,49 @@
+/* MIT License
+ *
+ * Copyright (c) 2023-2024 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+/// Hooks to gather metrics beyond those in the report, for code that
+/// uses this library. Each is given the read name without the "@",
+/// and all are called from one thread, in the order of the input,
+/// with the first end of paired reads before the second. Results
+/// are kept by the implementation, so it takes &self and can be
+/// shared with the caller, e.g. with atomics in an Arc.
+pub trait StatsCollector: Send + Sync {
+    /// A record as it is in the input.
+    fn on_record_in(&self, _name: &[u8], _seq: &[u8], _qual: &[u8]) {}
+
+    /// The length of a read before and after trimming, and the reason
+    /// if it was discarded.
+    fn on_trim(
+        &self,
+        _name: &[u8],
+        _len: usize,
+        _kept: usize,
+        _discard: Option<&str>,
+    ) {
+    }
+
+    /// A record as it is written to the output.
+    fn on_record_out(&self, _name: &[u8], _seq: &[u8], _qual: &[u8]) {}
+}
@@ -0,0 +1,161 @@
+/* MIT License
+ *
+ * Copyright (c) 2023-2024 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use crate::{open_input, pair_name};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::io::{BufRead, BufReader};
+
+/// A read name and its sequence.
+type Record = (Vec<u8>, Vec<u8>);
+
+/// The next record as its name, up to the first space and without any
+/// "/1" or "/2", and its sequence.
+fn next_record<R: BufRead>(
+    reader: &mut R,
+) -> Result<Option<Record>, Box<dyn Error>> {
+    let mut lines = [Vec::new(), Vec::new(), Vec::new(), Vec::new()];
+    for (i, line) in lines.iter_mut().enumerate() {
+        if reader.read_until(b'\n', line)? == 0 {
+            return match i {
+                0 => Ok(None),
+                _ => Err("incomplete record at end of input")?,
+            };
+        }
+        while line.last().is_some_and(|x| x.is_ascii_whitespace()) {
+            line.pop();
+        }
+    }
+    let [name, seq, _, _] = lines;
+    let name = pair_name(name.strip_prefix(b"@").unwrap_or(&name)).to_vec();
+    Ok(Some((name, seq)))
+}
+
+/// Where the sequences kept for the same read by two trimmers differ:
+/// one is the start of the other, so they differ at the 3' end, or
+/// the end of the other, so they differ at the 5' end.
+enum Difference {
+    Same,
+    End3(isize),
+    End5(isize),
+    Other,
+}
+
+fn difference(a: &[u8], b: &[u8]) -> Difference {
+    let d = a.len() as isize - b.len() as isize;
+    match (a.starts_with(b) || b.starts_with(a), d) {
+        (true, 0) => Difference::Same,
+        (true, _) => Difference::End3(d),
+        _ if a.ends_with(b) || b.ends_with(a) => Difference::End5(d),
+        _ => Difference::Other,
+    }
+}
+
+/// Compare the reads kept by another trimmer, e.g. cutadapt or fastp,
+/// with those kept by this one, matching reads by name. Reads that
+/// differ are listed, up to `show` of them, and then the counts of
+/// each kind of difference. Differences in length are this output
+/// minus the other.
+pub fn compare_trimmed(
+    ours: &str,
+    other: &str,
+    show: usize,
+) -> Result<(), Box<dyn Error>> {
+    let mut theirs = HashMap::new();
+    let mut reader = BufReader::new(open_input(other)?);
+    while let Some((name, seq)) = next_record(&mut reader)? {
+        theirs.insert(name, seq);
+    }
+
+    let (mut both, mut only_ours, mut shown) = (0, 0, 0);
+    let (mut same, mut other_diff) = (0, 0);
+    let mut end3: BTreeMap<isize, usize> = BTreeMap::new();
+    let mut end5: BTreeMap<isize, usize> = BTreeMap::new();
+    let mut reader = BufReader::new(open_input(ours)?);
+    while let Some((name, seq)) = next_record(&mut reader)? {
+        let their_seq = match theirs.remove(&name) {
+            Some(x) => x,
+            None => {
+                only_ours += 1;
+                continue;
+            }
+        };
+        both += 1;
+        let (what, d) = match difference(&seq, &their_seq) {
+            Difference::Same => {
+                same += 1;
+                continue;
+            }
+            Difference::End3(d) => {
+                *end3.entry(d).or_insert(0) += 1;
+                ("at 3' end", d)
+            }
+            Difference::End5(d) => {
+                *end5.entry(d).or_insert(0) += 1;
+                ("at 5' end", d)
+            }
+            Difference::Other => {
+                other_diff += 1;
+                (
+                    "not at an end",
+                    seq.len() as isize - their_seq.len() as isize,
+                )
+            }
+        };
+        if shown < show {
+            shown += 1;
+            println!(
+                "{}: differ {}, length {:+} ({} vs {})",
+                name.escape_ascii(),
+                what,
+                d,
+                seq.len(),
+                their_seq.len()
+            );
+        }
+    }
+
+    let frac = |x: usize| x as f64 / std::cmp::max(both, 1) as f64;
+    println!("reads in both: {}", both);
+    println!("reads only in {}: {}", ours, only_ours);
+    println!("reads only in {}: {}", other, theirs.len());
+    println!("same: {} ({:.4})", same, frac(same));
+    let n3: usize = end3.values().sum();
+    let n5: usize = end5.values().sum();
+    println!("differ at 3' end: {} ({:.4})", n3, frac(n3));
+    println!("differ at 5' end: {} ({:.4})", n5, frac(n5));
+    println!(
+        "differ not at an end: {} ({:.4})",
+        other_diff,
+        frac(other_diff)
+    );
+    for (d, n) in &end3 {
+        println!("3' end length {:+}: {}", d, n);
+    }
+    for (d, n) in &end5 {
+        println!("5' end length {:+}: {}", d, n);
+    }
+    Ok(())
+}
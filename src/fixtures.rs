@@ -0,0 +1,109 @@
+/* MIT License
+ *
+ * Copyright (c) 2023-2024 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+/// The adaptor trimmed when none is given.
+const ADAPTOR: &[u8] = b"AGATCGGAAGAGC";
+
+/// Bases for reads that do not contain the adaptor.
+const INSERT: &[u8] = b"ACGTTGCAACGTTGCA";
+
+/// One FASTQ record with all qualities the same.
+fn record(out: &mut Vec<u8>, name: &str, seq: &[u8]) {
+    out.extend_from_slice(format!("@{}\n", name).as_bytes());
+    out.extend_from_slice(seq);
+    out.extend_from_slice(b"\n+\n");
+    out.extend(std::iter::repeat_n(b'I', seq.len()));
+    out.push(b'\n');
+}
+
+/// Records of many lengths so that, with a buffer of size `buf_sz`,
+/// records end at or around the end of the buffer, including records
+/// split in each of their lines. Some have the adaptor, so the
+/// output changes if a record split in two is trimmed wrongly.
+pub fn buffer_boundary(buf_sz: usize) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while out.len() < 4 * buf_sz {
+        let len = 1 + i % (2 * INSERT.len());
+        let mut seq: Vec<u8> =
+            INSERT.iter().cycle().take(len).copied().collect();
+        if i % 3 == 0 {
+            seq.extend_from_slice(ADAPTOR);
+        }
+        record(&mut out, &format!("r{}", i), &seq);
+        i += 1;
+    }
+    out
+}
+
+/// Records with no bases, alone and between ordinary records.
+pub fn zero_length() -> Vec<u8> {
+    let mut out = Vec::new();
+    record(&mut out, "empty0", b"");
+    record(&mut out, "insert", INSERT);
+    record(&mut out, "empty1", b"");
+    record(&mut out, "empty2", b"");
+    let mut seq = INSERT.to_vec();
+    seq.extend_from_slice(ADAPTOR);
+    record(&mut out, "adaptor", &seq);
+    record(&mut out, "empty3", b"");
+    out
+}
+
+/// Records that are all adaptor, so nothing is left after trimming:
+/// the whole adaptor, the adaptor repeated, and prefixes of it.
+pub fn all_adaptor() -> Vec<u8> {
+    let mut out = Vec::new();
+    record(&mut out, "full", ADAPTOR);
+    let twice = [ADAPTOR, ADAPTOR].concat();
+    record(&mut out, "twice", &twice);
+    for i in 1..ADAPTOR.len() {
+        record(&mut out, &format!("prefix{}", i), &ADAPTOR[..i]);
+    }
+    out
+}
+
+/// Write all the fixtures as FASTQ files in `dir`, for a buffer of
+/// size `buf_sz`, and return the names of the files.
+pub fn write_fixtures(
+    dir: &Path,
+    buf_sz: usize,
+) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let fixtures = [
+        ("buffer_boundary.fq", buffer_boundary(buf_sz)),
+        ("zero_length.fq", zero_length()),
+        ("all_adaptor.fq", all_adaptor()),
+    ];
+    let mut files = Vec::new();
+    for (filename, data) in fixtures {
+        let file = dir.join(filename);
+        std::fs::write(&file, data)?;
+        files.push(file);
+    }
+    Ok(files)
+}
@@ -0,0 +1,225 @@
+/* MIT License
+ *
+ * Copyright (c) 2023-2024 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+// The parts of rust_htslib that are used here, written over the
+// htslib functions themselves. The rust_htslib BGZF writer always
+// ends a file with the empty EOF block, which some consumers of a
+// stream don't expect, and has no way to leave it out. Files share
+// one pool of threads for compression and decompression.
+
+use rust_htslib::htslib;
+use std::ffi::CString;
+use std::io::{Read, Write};
+use std::os::raw::{c_int, c_void};
+use std::path::Path;
+use std::rc::Rc;
+
+/// Open a file with htslib, where "-" is standard input or output.
+fn open<P: AsRef<Path>>(
+    path: P,
+    mode: &str,
+) -> std::io::Result<*mut htslib::BGZF> {
+    let name = path.as_ref().to_string_lossy();
+    let c_name = CString::new(name.as_bytes())?;
+    let c_mode = CString::new(mode)?;
+    let fp = unsafe { htslib::bgzf_open(c_name.as_ptr(), c_mode.as_ptr()) };
+    match fp.is_null() {
+        true => Err(std::io::Error::other(format!("failed to open: {}", name))),
+        false => Ok(fp),
+    }
+}
+
+pub mod tpool {
+    use super::*;
+
+    /// The htslib thread pool, which is destroyed only once no file
+    /// that uses it is still open.
+    pub(super) struct Pool(pub(super) *mut htslib::hts_tpool);
+
+    impl Drop for Pool {
+        fn drop(&mut self) {
+            unsafe { htslib::hts_tpool_destroy(self.0) }
+        }
+    }
+
+    pub struct ThreadPool(pub(super) Rc<Pool>);
+
+    impl ThreadPool {
+        pub fn new(n_threads: u32) -> std::io::Result<ThreadPool> {
+            let pool = unsafe { htslib::hts_tpool_init(n_threads as i32) };
+            if pool.is_null() {
+                return Err(std::io::Error::other("failed to start threads"));
+            }
+            Ok(ThreadPool(Rc::new(Pool(pool))))
+        }
+
+        /// Give a file the threads, keeping the pool while it is open.
+        pub(super) fn attach(
+            &self,
+            fp: *mut htslib::BGZF,
+        ) -> std::io::Result<Rc<Pool>> {
+            match unsafe { htslib::bgzf_thread_pool(fp, self.0 .0, 0) } {
+                0 => Ok(self.0.clone()),
+                _ => Err(std::io::Error::other("failed to use threads")),
+            }
+        }
+    }
+}
+
+pub mod bgzf {
+    use super::tpool::{Pool, ThreadPool};
+    use super::*;
+
+    #[derive(Clone, Copy)]
+    pub enum CompressionLevel {
+        Default,
+        Uncompressed,
+    }
+
+    /// Reads BGZF, gzip or plain text.
+    pub struct Reader {
+        fp: *mut htslib::BGZF,
+        _tpool: Option<Rc<Pool>>,
+    }
+
+    impl Reader {
+        pub fn from_path<P: AsRef<Path>>(path: P) -> std::io::Result<Reader> {
+            Ok(Reader {
+                fp: open(path, "r")?,
+                _tpool: None,
+            })
+        }
+        pub fn from_stdin() -> std::io::Result<Reader> {
+            Reader::from_path("-")
+        }
+        pub fn set_thread_pool(
+            &mut self,
+            tpool: &ThreadPool,
+        ) -> std::io::Result<()> {
+            self._tpool = Some(tpool.attach(self.fp)?);
+            Ok(())
+        }
+    }
+
+    impl Read for Reader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let data = buf.as_mut_ptr() as *mut c_void;
+            match unsafe { htslib::bgzf_read(self.fp, data, buf.len()) } {
+                n if n < 0 => Err(std::io::Error::other("read failed")),
+                n => Ok(n as usize),
+            }
+        }
+    }
+
+    impl Drop for Reader {
+        fn drop(&mut self) {
+            unsafe {
+                htslib::bgzf_close(self.fp);
+            }
+        }
+    }
+
+    /// Writes BGZF, ending it with the empty EOF block unless asked
+    /// not to, or plain text with no compression.
+    pub struct Writer {
+        fp: *mut htslib::BGZF,
+        eof_block: bool,
+        _tpool: Option<Rc<Pool>>,
+    }
+
+    impl Writer {
+        pub fn from_path_with_level<P: AsRef<Path>>(
+            path: P,
+            level: CompressionLevel,
+        ) -> std::io::Result<Writer> {
+            let mode = match level {
+                CompressionLevel::Default => "w",
+                CompressionLevel::Uncompressed => "wu",
+            };
+            Ok(Writer {
+                fp: open(path, mode)?,
+                eof_block: true,
+                _tpool: None,
+            })
+        }
+        /// Whether to end with the empty block, which some consumers
+        /// of a stream don't want.
+        pub fn set_eof_block(&mut self, eof_block: bool) {
+            self.eof_block = eof_block;
+        }
+        pub fn set_thread_pool(
+            &mut self,
+            tpool: &ThreadPool,
+        ) -> std::io::Result<()> {
+            self._tpool = Some(tpool.attach(self.fp)?);
+            Ok(())
+        }
+        /// Write the last block and close the file, with an error if
+        /// that fails, which dropping the writer can't give.
+        pub fn finish(mut self) -> std::io::Result<()> {
+            let fp = std::mem::replace(&mut self.fp, std::ptr::null_mut());
+            match unsafe { close(fp, self.eof_block) } {
+                0 => Ok(()),
+                _ => Err(std::io::Error::other("close failed")),
+            }
+        }
+    }
+
+    /// Close a file being written, giving the bgzf_close status. htslib
+    /// writes the EOF block when it closes a file it compressed, so
+    /// once the last block is out it is told the file is not compressed.
+    unsafe fn close(fp: *mut htslib::BGZF, eof_block: bool) -> c_int {
+        if !eof_block && htslib::bgzf_flush(fp) == 0 {
+            (*fp).set_is_compressed(0);
+        }
+        htslib::bgzf_close(fp)
+    }
+
+    impl Write for Writer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let data = buf.as_ptr() as *const c_void;
+            match unsafe { htslib::bgzf_write(self.fp, data, buf.len()) } {
+                n if n < 0 => Err(std::io::Error::other("write failed")),
+                n => Ok(n as usize),
+            }
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            match unsafe { htslib::bgzf_flush(self.fp) } {
+                0 => Ok(()),
+                _ => Err(std::io::Error::other("flush failed")),
+            }
+        }
+    }
+
+    impl Drop for Writer {
+        fn drop(&mut self) {
+            if !self.fp.is_null() {
+                unsafe {
+                    close(self.fp, self.eof_block);
+                }
+            }
+        }
+    }
+}
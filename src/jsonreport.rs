@@ -0,0 +1,150 @@
+/* MIT License
+ *
+ * Copyright (c) 2023-2024 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use crate::{PairStats, Stats};
+use std::io::Write;
+
+/// A string as JSON, in quotes and with the characters JSON does not
+/// allow in a string escaped.
+fn quote(s: &str) -> String {
+    let mut q = String::with_capacity(s.len() + 2);
+    q.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => q.push_str("\\\""),
+            '\\' => q.push_str("\\\\"),
+            '\n' => q.push_str("\\n"),
+            '\t' => q.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                q.push_str(&format!("\\u{:04x}", c as u32))
+            }
+            c => q.push(c),
+        }
+    }
+    q.push('"');
+    q
+}
+
+fn write_stats<W: Write>(
+    out: &mut W,
+    input: &str,
+    stats: &Stats,
+) -> std::io::Result<()> {
+    writeln!(out, "    {{")?;
+    writeln!(out, "      \"input\": {},", quote(input))?;
+    writeln!(out, "      \"reads\": {},", stats.reads)?;
+    writeln!(out, "      \"reads_kept\": {},", stats.reads_kept)?;
+    writeln!(out, "      \"reads_written\": {},", stats.written)?;
+    writeln!(out, "      \"bases\": {},", stats.bases)?;
+    writeln!(out, "      \"bases_kept\": {},", stats.bases_kept)?;
+    writeln!(out, "      \"bases_trimmed\": {{")?;
+    writeln!(out, "        \"adaptor\": {},", stats.cut_adaptor)?;
+    writeln!(out, "        \"quality\": {},", stats.cut_quality)?;
+    writeln!(out, "        \"n\": {},", stats.cut_n)?;
+    writeln!(out, "        \"other\": {}", stats.cut_other)?;
+    writeln!(out, "      }},")?;
+    writeln!(out, "      \"reads_with_adaptor\": {},", stats.with_adaptor)?;
+    let discarded: Vec<String> = stats
+        .discarded
+        .iter()
+        .map(|(reason, count)| format!("{}: {}", quote(reason), count))
+        .collect();
+    writeln!(out, "      \"discarded\": {{{}}},", discarded.join(", "))?;
+    // only lengths seen, as [length, reads before, reads after]
+    let lengths: Vec<String> = stats
+        .lengths
+        .iter()
+        .enumerate()
+        .filter(|(_, (before, after))| before + after > 0)
+        .map(|(len, (before, after))| {
+            format!("[{}, {}, {}]", len, before, after)
+        })
+        .collect();
+    writeln!(out, "      \"lengths\": [{}]", lengths.join(", "))?;
+    write!(out, "    }}")
+}
+
+fn write_pairs<W: Write>(
+    out: &mut W,
+    pairs: &PairStats,
+) -> std::io::Result<()> {
+    writeln!(out, "  \"pairs\": {{")?;
+    writeln!(out, "    \"pairs\": {},", pairs.pairs)?;
+    writeln!(out, "    \"pairs_kept\": {},", pairs.pairs_kept)?;
+    writeln!(out, "    \"discarded_one\": {},", pairs.discarded_one)?;
+    writeln!(out, "    \"discarded_both\": {},", pairs.discarded_both)?;
+    writeln!(out, "    \"overlapping\": {},", pairs.overlapping)?;
+    // as [insert size, pairs]
+    let inserts: Vec<String> = pairs
+        .inserts
+        .iter()
+        .enumerate()
+        .filter(|(_, &n)| n > 0)
+        .map(|(i, n)| format!("[{}, {}]", i, n))
+        .collect();
+    writeln!(out, "    \"inserts\": [{}]", inserts.join(", "))?;
+    write!(out, "  }}")
+}
+
+/// Write the summary of a run as JSON: the parameters, as given or
+/// by default, then the counts for each input, and for paired reads
+/// those of the pairs. Parameters with more than one value are given
+/// as arrays.
+pub fn write_json_report<W: Write>(
+    out: &mut W,
+    parameters: &[(String, Vec<String>)],
+    summaries: &[(String, Stats)],
+    pairs: Option<&PairStats>,
+) -> std::io::Result<()> {
+    writeln!(out, "{{")?;
+    writeln!(out, "  \"version\": {},", quote(env!("CARGO_PKG_VERSION")))?;
+    writeln!(out, "  \"parameters\": {{")?;
+    for (i, (name, values)) in parameters.iter().enumerate() {
+        let value = match &values[..] {
+            [x] => quote(x),
+            _ => {
+                let x: Vec<String> = values.iter().map(|x| quote(x)).collect();
+                format!("[{}]", x.join(", "))
+            }
+        };
+        let sep = if i + 1 < parameters.len() { "," } else { "" };
+        writeln!(out, "    {}: {}{}", quote(name), value, sep)?;
+    }
+    writeln!(out, "  }},")?;
+    writeln!(out, "  \"inputs\": [")?;
+    for (i, (input, stats)) in summaries.iter().enumerate() {
+        write_stats(out, input, stats)?;
+        writeln!(out, "{}", if i + 1 < summaries.len() { "," } else { "" })?;
+    }
+    match pairs {
+        Some(pairs) => {
+            writeln!(out, "  ],")?;
+            write_pairs(out, pairs)?;
+            writeln!(out)?;
+        }
+        None => writeln!(out, "  ]")?,
+    }
+    writeln!(out, "}}")
+}
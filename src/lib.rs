@@ -24,20 +24,59 @@
  */
 
 use rayon::prelude::*;
-use std::cmp::{max, min};
-use std::io::{Read, Write};
-use std::ptr;
+use std::cell::RefCell;
+use std::cmp::{max, min, Reverse};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
 use std::error::Error;
 
+mod collector;
+mod compare;
+#[cfg(any(test, feature = "fixtures"))]
+pub mod fixtures;
+#[cfg(not(feature = "pure-rust"))]
+mod htsio;
+mod jsonreport;
+mod monitor;
+mod qualtrim;
+mod reportdiff;
+#[cfg(feature = "pure-rust")]
+mod rustio;
+mod screen;
+mod selftest;
+mod sweep;
+pub use collector::StatsCollector;
+pub use compare::compare_trimmed;
+pub use jsonreport::write_json_report;
+pub use qualtrim::{Cutadapt, Mott, QualityTrimmer, Window};
+pub use reportdiff::report_diff;
+pub use screen::Screen;
+pub use selftest::self_test;
+pub use sweep::sweep;
+
+use monitor::{input_size, Monitor, Stage};
+
 // the rust_htslib crate is not ideal for our purpose
-use rust_htslib::bgzf;
-use rust_htslib::bgzf::CompressionLevel as CompLvl;
-use rust_htslib::tpool::ThreadPool;
+#[cfg(not(feature = "pure-rust"))]
+use htsio::{bgzf, tpool::ThreadPool};
+#[cfg(not(feature = "pure-rust"))]
+use rust_htslib::htslib;
+#[cfg(feature = "pure-rust")]
+use rustio::{bgzf, tpool::ThreadPool, GzipWriter};
+use bgzf::CompressionLevel as CompLvl;
+
+#[cfg(not(any(feature = "htslib", feature = "pure-rust")))]
+compile_error!("either the htslib or the pure-rust feature is needed");
 
 /// The prefix function for the KMP algorithm
 fn kmp_prefix_function(p: &[u8]) -> Vec<usize> {
     let n = p.len();
-    let mut sp = vec![0 as usize; n];
+    let mut sp = vec![0usize; n];
     let mut k = 0usize;
     for i in 1..n {
         while k > 0 && p[k] != p[i] {
@@ -55,6 +94,9 @@ fn kmp_prefix_function(p: &[u8]) -> Vec<usize> {
 /// of any suffix match to the pattern (i.e. adaptor).
 fn kmp(adaptor: &[u8], sp: &[usize], read: &[u8], m: usize) -> usize {
     let n = adaptor.len();
+    if n == 0 {
+        return m; // no adaptor to find
+    }
     let mut j: usize = 0;
     let mut i: usize = 0;
     while i < m {
@@ -79,64 +121,585 @@ fn kmp(adaptor: &[u8], sp: &[usize], read: &[u8], m: usize) -> usize {
     i - j
 }
 
-/// Find the positions in the read of the first non-N and last non-N.
-fn trim_n_ends(read: &[u8]) -> (usize, usize) {
-    let start = match read.iter().position(|&x| x != b'N') {
-        Some(x) => x,
-        _ => 0,
-    };
-    let stop = match read.iter().rposition(|&x| x != b'N') {
-        Some(x) => x + 1,
-        _ => 0,
-    };
-    (start, stop)
+/// Check if a base in the read matches a base in an adaptor, which
+/// can be an IUPAC code for more than one base. An N in the read only
+/// matches an N in the adaptor.
+fn iupac_match(adaptor: u8, read: u8) -> bool {
+    adaptor == read
+        || match adaptor {
+            b'N' => true,
+            b'R' => matches!(read, b'A' | b'G'),
+            b'Y' => matches!(read, b'C' | b'T'),
+            b'S' => matches!(read, b'C' | b'G'),
+            b'W' => matches!(read, b'A' | b'T'),
+            b'K' => matches!(read, b'G' | b'T'),
+            b'M' => matches!(read, b'A' | b'C'),
+            b'B' => matches!(read, b'C' | b'G' | b'T'),
+            b'D' => matches!(read, b'A' | b'G' | b'T'),
+            b'H' => matches!(read, b'A' | b'C' | b'T'),
+            b'V' => matches!(read, b'A' | b'C' | b'G'),
+            _ => false,
+        }
+}
+
+/// The same search as `kmp` for an adaptor with IUPAC codes, which
+/// the prefix function cannot be used for, checking each position.
+/// Up to `max_err` mismatches are allowed, but no more than the
+/// stringency allows for the overlap, so short overlaps at the end of
+/// the read must match exactly.
+fn iupac_search(
+    adaptor: &[u8],
+    read: &[u8],
+    m: usize,
+    max_err: usize,
+    stringency: Stringency,
+) -> usize {
+    let n = adaptor.len();
+    (0..m)
+        .find(|&i| {
+            let k = min(n, m - i);
+            let allowed = stringency.allowed(max_err, k);
+            let mut mm = 0;
+            adaptor[..k].iter().zip(&read[i..]).all(|(&a, &r)| {
+                mm += !iupac_match(a, r) as usize;
+                mm <= allowed
+            })
+        })
+        .unwrap_or(m)
 }
 
-/// Find the positions in the read where quality scores indicate the
-/// read should be trimmed. This is copied from cutadapt source.
-fn qual_trim(qual: &[u8], cut_front: i32, cut_back: i32) -> (usize, usize) {
-    const QUAL_BASE: i32 = 33; // assumes base quality starts at 33
+/// The bit masks for the shift-and algorithm: for each character,
+/// the positions in the adaptor it matches. Only for adaptors with
+/// IUPAC codes that fit in the bits of a word; empty otherwise.
+fn shift_and_masks(adaptor: &[u8]) -> Vec<u64> {
+    if !is_degenerate(adaptor) || adaptor.len() > u64::BITS as usize {
+        return Vec::new();
+    }
+    let mut masks = vec![0u64; 256];
+    for (c, mask) in masks.iter_mut().enumerate() {
+        for (j, &a) in adaptor.iter().enumerate() {
+            if iupac_match(a, c as u8) {
+                *mask |= 1 << j;
+            }
+        }
+    }
+    masks
+}
+
+/// The shift-and algorithm, which gives the same result as `kmp` but
+/// allows IUPAC codes, since it tracks all prefixes of the adaptor
+/// that match, one bit for each, at once.
+fn shift_and(masks: &[u64], n: usize, read: &[u8], m: usize) -> usize {
+    if n == 0 {
+        return m; // no adaptor to find
+    }
+    let full = 1u64 << (n - 1);
+    let mut d = 0u64;
+    for (i, &x) in read[..m].iter().enumerate() {
+        d = ((d << 1) | 1) & masks[x as usize];
+        if d & full != 0 {
+            return (i + 1) - n;
+        }
+    }
+    // the longest prefix of the adaptor at the end of the read
+    match d {
+        0 => m,
+        _ => m - (u64::BITS - d.leading_zeros()) as usize,
+    }
+}
 
-    /* ADS: COPIED FROM cutadapt SOURCE */
-    let n = qual.len();
+/// Check if a sequence has any IUPAC codes other than ACGT.
+fn is_degenerate(seq: &[u8]) -> bool {
+    seq.iter().any(|x| !b"ACGT".contains(x))
+}
 
-    //  find trim position for 5' end
-    let mut start: usize = 0;
-    let mut s: i32 = 0;
-    let mut max_qual: i32 = 0;
+/// How closely an adaptor must match to be trimmed: at least
+/// `min_overlap` of its bases must be in the read, or all of it if it
+/// is shorter, and at least `frac` of those must match, which only
+/// matters if `Params::max_errors` allows mismatches.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Stringency {
+    pub min_overlap: usize,
+    pub frac: f64,
+}
 
-    if cut_front > 0 {
-        let cut_front = cut_front + QUAL_BASE;
-        for i in 0..n {
-            s += (cut_front + QUAL_BASE) - qual[i] as i32;
-            if s < 0 {
-                break;
+impl Default for Stringency {
+    fn default() -> Stringency {
+        Stringency {
+            min_overlap: 1,
+            frac: 0.9,
+        }
+    }
+}
+
+impl Stringency {
+    /// The mismatches allowed where `k` bases of an adaptor are in the
+    /// read, at most `max_err`.
+    fn allowed(&self, max_err: usize, k: usize) -> usize {
+        // a product that should be whole can be just below it
+        let by_frac = (k as f64 * (1.0 - self.frac) + 1e-9) as usize;
+        min(max_err, by_frac)
+    }
+}
+
+/// An adaptor with its KMP prefix function. A 5' adaptor is kept
+/// reversed, so it can be found with the same search in the reversed
+/// read, unless it is anchored to the start of the read.
+struct Adaptor {
+    seq: Vec<u8>,
+    sp: Vec<usize>,
+    anchored: bool,
+    degenerate: bool, // has IUPAC codes other than ACGT
+    masks: Vec<u64>,  // for shift-and if degenerate and short enough
+    max_err: usize,   // mismatches allowed
+    stringency: Stringency,
+}
+
+impl Adaptor {
+    fn new(seq: &[u8]) -> Adaptor {
+        Adaptor {
+            seq: seq.to_vec(),
+            sp: kmp_prefix_function(seq),
+            anchored: false,
+            degenerate: is_degenerate(seq),
+            masks: shift_and_masks(seq),
+            max_err: 0,
+            stringency: Stringency::default(),
+        }
+    }
+
+    /// A 5' adaptor, anchored if it starts with "^" as in cutadapt.
+    fn front(seq: &[u8]) -> Adaptor {
+        match seq.strip_prefix(b"^") {
+            Some(x) => Adaptor {
+                seq: x.to_vec(),
+                sp: Vec::new(),
+                anchored: true,
+                degenerate: is_degenerate(x),
+                masks: Vec::new(),
+                max_err: 0,
+                stringency: Stringency::default(),
+            },
+            None => {
+                let seq: Vec<u8> = seq.iter().rev().copied().collect();
+                let sp = kmp_prefix_function(&seq);
+                Adaptor {
+                    degenerate: is_degenerate(&seq),
+                    masks: shift_and_masks(&seq),
+                    seq,
+                    sp,
+                    anchored: false,
+                    max_err: 0,
+                    stringency: Stringency::default(),
+                }
             }
-            if s > max_qual {
-                max_qual = s;
-                start = i + 1;
+        }
+    }
+
+    /// Find the adaptor in the first `m` bases of the read, as `kmp`.
+    /// Only exact matches can use the faster searches.
+    fn find(&self, read: &[u8], m: usize) -> usize {
+        let n = self.seq.len();
+        let i = match (self.max_err, self.degenerate, self.masks.is_empty()) {
+            (0, false, _) => kmp(&self.seq, &self.sp, read, m),
+            (0, true, false) => shift_and(&self.masks, n, read, m),
+            _ => {
+                iupac_search(&self.seq, read, m, self.max_err, self.stringency)
             }
+        };
+        // too little of the adaptor at the end of the read to trim
+        match m - i < min(self.stringency.min_overlap, n) {
+            true => m,
+            false => i,
         }
     }
-    // same for 3' end
-    let mut stop: usize = n;
-    max_qual = 0;
-    s = 0;
-    let cut_back = cut_back + QUAL_BASE;
-    for i in (0..n).rev() {
-        s += cut_back - qual[i] as i32;
-        if s < 0 {
-            break;
+
+    /// The mismatches of the adaptor placed at `i` in the read, over
+    /// the part of it that fits, and the length of that part.
+    fn score(&self, read: &[u8], i: usize) -> (usize, usize) {
+        let k = min(self.seq.len(), read.len() - i);
+        let mm = self.seq[..k]
+            .iter()
+            .zip(&read[i..])
+            .filter(|(&a, &r)| !iupac_match(a, r))
+            .count();
+        (mm, k)
+    }
+
+    /// The number of bases to remove from the start of the read for a
+    /// 5' adaptor: up to the end of the last full match, or the length
+    /// of a suffix of the adaptor at the start of the read.
+    fn front_end(&self, read: &[u8]) -> usize {
+        if self.anchored {
+            let n = self.seq.len();
+            let allowed = self.stringency.allowed(self.max_err, n);
+            return match_prefix(&self.seq, read, allowed);
+        }
+        REVERSED.with(|rev| {
+            let mut rev = rev.borrow_mut();
+            rev.clear();
+            rev.extend(read.iter().rev());
+            read.len() - self.find(&rev, rev.len())
+        })
+    }
+}
+
+thread_local! {
+    /// A read reversed to look for 5' adaptors, kept for each thread
+    /// so there is no allocation for each read.
+    static REVERSED: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+
+    /// The adaptor from the comment of the last read that had one,
+    /// since reads from one sample are usually together.
+    static TAGGED: RefCell<Option<Adaptor>> = const { RefCell::new(None) };
+}
+
+/// The value of a field in the comment of a read name, given either
+/// as "TAG=VALUE" or as a SAM tag "TAG:Z:VALUE".
+fn comment_field<'a>(name: &'a [u8], tag: &[u8]) -> Option<&'a [u8]> {
+    let i = name.iter().position(|x| x.is_ascii_whitespace())?;
+    name[i..]
+        .split(|x| x.is_ascii_whitespace())
+        .filter_map(|x| x.strip_prefix(tag))
+        .find_map(|x| x.strip_prefix(b"=").or_else(|| x.strip_prefix(b":Z:")))
+        .filter(|x| !x.is_empty())
+}
+
+/// All the adaptors to find in a read: at the 3' end, at the 5' end,
+/// and linked pairs of 5' and 3' adaptors that must both be found.
+struct Adaptors {
+    stringency: Stringency, // for 3' adaptors, also those in comments
+    back: Vec<Adaptor>,
+    front: Vec<Adaptor>,
+    linked: Vec<(Adaptor, Adaptor)>,
+    primers_rc: Vec<Vec<u8>>, // as they are read through after the insert
+    backbone: Option<Adaptor>, // between copies of the insert in concatemers
+}
+
+impl Adaptors {
+    /// The 3' adaptors are given separately so each end of paired
+    /// reads can have its own, and `end` is 0 or 1 for the stringency
+    /// of each end.
+    fn new(back: &[Vec<u8>], params: &Params, end: usize) -> Adaptors {
+        let (back_stringency, front_stringency) = params.stringency[end];
+        Adaptors {
+            stringency: back_stringency,
+            back: back
+                .iter()
+                .map(|x| Adaptor {
+                    max_err: params.max_errors,
+                    stringency: back_stringency,
+                    ..Adaptor::new(x)
+                })
+                .collect(),
+            front: params
+                .front
+                .iter()
+                .map(|x| Adaptor {
+                    max_err: params.max_errors,
+                    stringency: front_stringency,
+                    ..Adaptor::front(x)
+                })
+                .collect(),
+            linked: params
+                .linked
+                .iter()
+                .map(|(a, b)| (Adaptor::front(a), Adaptor::new(b)))
+                .collect(),
+            primers_rc: params
+                .primers
+                .iter()
+                .map(|x| x.iter().rev().map(|&b| complement(b)).collect())
+                .collect(),
+            backbone: match params.concatemer.is_empty() {
+                true => None,
+                false => Some(Adaptor::new(&params.concatemer)),
+            },
+        }
+    }
+
+    /// The start of the longest reverse-complemented primer at the
+    /// end of the read, if there is one, and the end of the read
+    /// otherwise. IUPAC codes in primers match any of their bases.
+    fn primer_start(&self, read: &[u8]) -> usize {
+        self.primers_rc
+            .iter()
+            .filter(|p| p.len() <= read.len())
+            .filter(|p| {
+                let tail = &read[read.len() - p.len()..];
+                p.iter().zip(tail).all(|(&a, &b)| iupac_match(a, b))
+            })
+            .map(|p| read.len() - p.len())
+            .min()
+            .unwrap_or(read.len())
+    }
+
+    /// The part of the read between the first linked adaptors that
+    /// are both found, the 5' adaptor and then the 3' adaptor.
+    fn find_linked(&self, read: &[u8]) -> Option<(usize, usize)> {
+        self.linked.iter().find_map(|(front, back)| {
+            let start = front.front_end(read);
+            if start == 0 {
+                return None;
+            }
+            let rest = &read[start..];
+            let stop = start + back.find(rest, rest.len());
+            (stop < read.len()).then_some((start, stop))
+        })
+    }
+}
+
+/// Describe where an adaptor was found for --explain.
+fn explain_pos(i: usize, stop: usize) -> String {
+    match i < stop {
+        true => format!("found at {}", i),
+        false => "not found".to_string(),
+    }
+}
+
+/// Check for a fixed sequence at the start of the read, allowing up
+/// to `max_mm` mismatches, where IUPAC codes in the sequence match
+/// any of their bases. Returns the length of the prefix if it is
+/// found, and 0 otherwise.
+fn match_prefix(prefix: &[u8], read: &[u8], max_mm: usize) -> usize {
+    if prefix.is_empty() || read.len() < prefix.len() {
+        return 0;
+    }
+    let mm = prefix
+        .iter()
+        .zip(read)
+        .filter(|(&a, &b)| !iupac_match(a, b))
+        .count();
+    if mm <= max_mm {
+        prefix.len()
+    } else {
+        0
+    }
+}
+
+/// Look for a strand invasion artifact: the template-switch oligo
+/// after the start of the read and followed by a run of C. Returns
+/// the position of the oligo if one is found.
+fn find_strand_invasion(
+    tso: &[u8],
+    read: &[u8],
+    from: usize,
+    max_mm: usize,
+) -> Option<usize> {
+    const MIN_POLY_C: usize = 3;
+    let n = tso.len();
+    if n == 0 || read.len() < n + MIN_POLY_C {
+        return None;
+    }
+    (max(from, 1)..=read.len() - n - MIN_POLY_C).find(|&i| {
+        read[i + n..i + n + MIN_POLY_C].iter().all(|&x| x == b'C')
+            && match_prefix(tso, &read[i..], max_mm) > 0
+    })
+}
+
+/// Complement of a nucleotide, leaving anything else as it is.
+fn complement(x: u8) -> u8 {
+    match x {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        // IUPAC codes, which can be in primers
+        b'R' => b'Y',
+        b'Y' => b'R',
+        b'K' => b'M',
+        b'M' => b'K',
+        b'B' => b'V',
+        b'V' => b'B',
+        b'D' => b'H',
+        b'H' => b'D',
+        _ => x,
+    }
+}
+
+/// Decide if a read should be reverse-complemented to put it in the
+/// orientation of the signature sequence: only if the reverse
+/// complement of the signature is found and the signature is not.
+fn needs_flip(signature: &[u8], read: &[u8]) -> bool {
+    let n = signature.len();
+    if n == 0 || read.len() < n {
+        return false;
+    }
+    let is_rc = |w: &[u8]| {
+        w.iter()
+            .zip(signature.iter().rev())
+            .all(|(&a, &b)| a == complement(b))
+    };
+    !read.windows(n).any(|w| w == signature) && read.windows(n).any(is_rc)
+}
+
+/// Reverse-complement a read and reverse its quality scores.
+fn reverse_complement(seq: &mut [u8], qual: &mut [u8]) {
+    seq.reverse();
+    seq.iter_mut().for_each(|x| *x = complement(*x));
+    qual.reverse();
+}
+
+/// Look for a hairpin adaptor joining the two strands of a duplex
+/// read. Only the middle half of the read is searched, since that is
+/// where the hairpin is if the two strands are both present. The
+/// bases on each side of it must be reverse complements, with up to
+/// one mismatch in ten, so the hairpin sequence in a read of only one
+/// strand doesn't split it. Returns the start and end of the hairpin.
+fn find_hairpin(hairpin: &[u8], read: &[u8]) -> Option<(usize, usize)> {
+    const ERR_BASES: usize = 10;
+    let n = hairpin.len();
+    if n == 0 || read.len() < 2 * n {
+        return None;
+    }
+    // the first strand read backwards against the second
+    let arms_match = |i: usize| {
+        let (left, right) = (&read[..i], &read[i + n..]);
+        let k = min(left.len(), right.len());
+        let mm = left
+            .iter()
+            .rev()
+            .zip(right)
+            .filter(|(&a, &b)| a != complement(b))
+            .count();
+        mm <= k / ERR_BASES
+    };
+    let (lo, hi) = (read.len() / 4, (3 * read.len() / 4).saturating_sub(n));
+    (lo..=hi)
+        .filter(|&i| &read[i..i + n] == hairpin)
+        .find(|&i| arms_match(i))
+        .map(|i| (i, i + n))
+}
+
+/// The parts of a concatemer read, as from rolling-circle
+/// amplification, between copies of the backbone: each is one copy
+/// of the insert, except those cut short at the ends of the read. A
+/// read without a full copy of the backbone gives nothing.
+fn find_subreads(backbone: &Adaptor, read: &[u8]) -> Vec<(usize, usize)> {
+    let n = backbone.seq.len();
+    let mut subreads = Vec::new();
+    let (mut from, mut full) = (0, false);
+    while from < read.len() {
+        let i = from + backbone.find(&read[from..], read.len() - from);
+        if i > from {
+            subreads.push((from, i));
         }
-        if s > max_qual {
-            max_qual = s;
-            stop = i;
+        // otherwise only part of the backbone is at the end
+        full |= i + n <= read.len();
+        from = min(i + n, read.len());
+    }
+    match full {
+        true => subreads,
+        false => Vec::new(),
+    }
+}
+
+/// Move a trim point `i` that is inside a homopolymer to its left
+/// edge, removing the whole homopolymer, or its right edge, keeping it.
+fn homopolymer_edge(read: &[u8], i: usize, edge: HomopolymerEdge) -> usize {
+    if i == 0 || i >= read.len() || read[i - 1] != read[i] {
+        return i;
+    }
+    let base = read[i];
+    match edge {
+        HomopolymerEdge::Extend => read[..i]
+            .iter()
+            .rposition(|&x| x != base)
+            .map_or(0, |j| j + 1),
+        HomopolymerEdge::Retract => read[i..]
+            .iter()
+            .position(|&x| x != base)
+            .map_or(read.len(), |j| i + j),
+    }
+}
+
+/// The start of a run of `base` at the end of the read, if the run
+/// has at least `min_run` bases, and the end of the read otherwise.
+fn poly_x_start(read: &[u8], base: u8, min_run: usize) -> usize {
+    let run = read.iter().rev().take_while(|&&x| x == base).count();
+    match run >= min_run {
+        true => read.len() - run,
+        false => read.len(),
+    }
+}
+
+/// The number of bases with quality at least 30.
+fn count_q30(qual: &[u8]) -> usize {
+    const Q30: u8 = 30 + 33; // assumes base quality starts at 33
+    qual.iter().filter(|&&x| x >= Q30).count()
+}
+
+/// The expected number of errors in a read: the sum of the error
+/// probabilities from its quality scores.
+fn expected_errors(qual: &[u8]) -> f64 {
+    const QUAL_BASE: u8 = 33; // assumes base quality starts at 33
+    qual.iter()
+        .map(|&x| 10f64.powf(-(x.saturating_sub(QUAL_BASE) as f64) / 10.0))
+        .sum()
+}
+
+/// The complexity of a read, from 0 to 1: the entropy of its 3-mers
+/// over the most it could be for its length. Homopolymers are 0 and
+/// simple repeats, like a dinucleotide repeat, are low. Reads with
+/// fewer than two 3-mers are 0.
+fn complexity(read: &[u8]) -> f64 {
+    const K: usize = 3;
+    let mut counts = [0usize; 1 << (2 * K)];
+    let code = |x: u8| match x {
+        b'A' => Some(0),
+        b'C' => Some(1),
+        b'G' => Some(2),
+        b'T' => Some(3),
+        _ => None,
+    };
+    let mut n = 0;
+    for w in read.windows(K) {
+        let kmer = w.iter().try_fold(0, |acc, &x| Some((acc << 2) | code(x)?));
+        if let Some(i) = kmer {
+            counts[i] += 1;
+            n += 1;
         }
     }
-    if start >= stop {
-        (start, stop) = (0, 0)
+    if n < 2 {
+        return 0.0;
+    }
+    let entropy: f64 = counts
+        .iter()
+        .filter(|&&c| c > 0)
+        .map(|&c| {
+            let p = c as f64 / n as f64;
+            -p * p.log2()
+        })
+        .sum();
+    entropy / (min(n, counts.len()) as f64).log2()
+}
+
+/// Find the positions in the read of the first non-N and last non-N.
+/// With `window` as (w, k), the read also ends at the first internal
+/// N of any w consecutive bases that include at least k Ns.
+fn trim_n_ends(read: &[u8], window: Option<(usize, usize)>) -> (usize, usize) {
+    let start = read.iter().position(|&x| x != b'N').unwrap_or_default();
+    let mut stop = match read.iter().rposition(|&x| x != b'N') {
+        Some(x) => x + 1,
+        _ => 0,
+    };
+    if let Some((w, k)) = window {
+        // Ns in the last w bases, counted as the window slides
+        let mut ns = 0;
+        for i in start..stop {
+            ns += (read[i] == b'N') as usize;
+            if i >= start + w {
+                ns -= (read[i - w] == b'N') as usize;
+            }
+            if ns >= k {
+                let from = max(start, (i + 1).saturating_sub(w));
+                let first = read[from..=i].iter().position(|&x| x == b'N');
+                stop = from + first.unwrap_or(0);
+                break;
+            }
+        }
     }
-    (start as usize, stop as usize)
+    (start, stop)
 }
 
 fn shift(buf: &mut [u8], cursor: &mut usize, filled: &mut usize) {
@@ -149,21 +712,77 @@ fn shift(buf: &mut [u8], cursor: &mut usize, filled: &mut usize) {
     *cursor = 0;
 }
 
+/// Remove the "\r" of each "\r\n" line end, as in files from
+/// Windows, starting at `from`. A "\r" at the very end is kept, since
+/// its "\n" may come with the next read.
+fn strip_cr(buf: &mut [u8], from: usize, filled: &mut usize) {
+    let mut j = from;
+    for i in from..*filled {
+        if buf[i] == b'\r' && i + 1 < *filled && buf[i + 1] == b'\n' {
+            continue;
+        }
+        buf[j] = buf[i];
+        j += 1;
+    }
+    *filled = j;
+}
+
 fn next_line(buf: &mut [u8], filled: usize, offset: usize) -> usize {
-    for i in offset..filled {
-        if buf[i] == b'\n' {
-            return i + 1;
+    // the offset is past the end if an earlier line was not found
+    buf.get(offset..filled)
+        .and_then(|x| x.iter().position(|&x| x == b'\n'))
+        .map_or(usize::MAX, |i| offset + i + 1)
+}
+
+/// Reasons a read can be dropped from the output. When discarded
+/// reads are kept, the name of the reason is appended to the name
+/// line of the read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Discard {
+    TooShort,
+    TooManyN,
+    TooManyErrors,
+    LowComplexity,
+    StrandInvasion,
+    MateDiscarded,
+    NoBarcode,
+}
+
+impl Discard {
+    fn name(&self) -> &'static str {
+        match self {
+            Discard::TooShort => "too_short",
+            Discard::TooManyN => "too_many_n",
+            Discard::TooManyErrors => "too_many_errors",
+            Discard::LowComplexity => "low_complexity",
+            Discard::StrandInvasion => "strand_invasion",
+            Discard::MateDiscarded => "mate_discarded",
+            Discard::NoBarcode => "no_barcode",
         }
     }
-    usize::MAX
 }
 
+/// An adaptor starting this close to the start of a read means the
+/// read is an adaptor dimer, with almost no insert.
+const DIMER_MAX_INSERT: usize = 3;
+
+/// Batches with fewer reads than this are processed in one thread.
+const MIN_PARALLEL_RECS: usize = 1024;
+
 /// FQRec is a FASTQ record that represents the position of the start
 /// of the name (n), the start of the read sequence (r), the start of
 /// the other name, the one with the "+" (o), and the start of the
 /// quality scores (q). The `start` and `stop` variables are used to
 /// store the offsets of trimmed ends for the read and quality scores
-/// strings.
+/// strings. If the read should not be in the output, `discard` holds
+/// the reason. The `invasion` flag marks strand invasion artifacts
+/// and `flipped` marks reads that were reverse-complemented. The
+/// original length of the read is `len` and `adaptor` marks reads
+/// where the adaptor was found, with `dimer` marking those where it
+/// is at the very start, `front_adaptor` those with a 5' adaptor and
+/// `linked` those with linked adaptors. If the read is split at a
+/// hairpin, `hairpin` is where the hairpin starts and ends, and if it
+/// is a concatemer, `subreads` are the parts between the backbones.
 #[derive(Default)]
 struct FQRec {
     n: usize,     // start of "name"
@@ -173,6 +792,26 @@ struct FQRec {
     e: usize,     // end of the record
     start: usize, // where good part of seq starts
     stop: usize,  // where good part of seq stops
+    discard: Option<Discard>,
+    invasion: bool,
+    flipped: bool,
+    len: usize,
+    adaptor: bool,
+    front_adaptor: bool,
+    linked: bool,
+    poly_x: bool,
+    primer: bool,
+    dimer: bool,
+    tagged: bool, // adaptor taken from the read comment
+    hairpin: Option<(usize, usize)>,
+    subreads: Vec<(usize, usize)>,
+    trimmed: (usize, usize), // start and stop before the read is moved
+    insert: Option<usize>,   // insert size from the overlap with the mate
+    q30: usize,              // bases with quality at least 30
+    q30_kept: usize,         // the same for the bases kept
+    cut_adaptor: usize,      // bases trimmed for the 3' adaptor
+    cut_quality: usize,      // bases trimmed for low quality
+    cut_n: usize,            // bases trimmed for N at the ends
 }
 
 impl std::fmt::Display for FQRec {
@@ -186,27 +825,296 @@ impl std::fmt::Display for FQRec {
 }
 
 impl FQRec {
+    /// Trim the record, changing it in place in `rec`, which is only
+    /// this record's part of the buffer, so that other threads can
+    /// change the other records at the same time.
     fn process(
         &mut self,
-        adaptor: &[u8],
-        sp: &Vec<usize>,
-        cutoff: u8,
-        buf: &Vec<u8>,
+        params: &Params,
+        adaptors: &Adaptors,
+        rec: &mut [u8],
     ) {
+        // offsets in the record while it is trimmed, then back to
+        // offsets in the buffer
+        let base = self.n;
+        self.offsets().into_iter().for_each(|x| *x -= base);
+        self.trim(params, adaptors, rec);
+        self.offsets().into_iter().for_each(|x| *x += base);
+    }
+    /// Keep a barcode read as it is, only checking that it is long
+    /// enough for the cell barcode and UMI at its start.
+    fn keep_barcode(&mut self, params: &Params, rec: &mut [u8]) {
+        let (q, len) = (self.q - self.n, self.stop);
+        if params.phred64 {
+            for x in &mut rec[q..q + len] {
+                *x -= 31;
+            }
+        }
+        self.len = len;
+        self.trimmed = (0, len);
+        self.q30 = count_q30(&rec[q..q + len]);
+        self.q30_kept = self.q30;
+        if params.barcode.is_some_and(|(cb, umi)| len < cb + umi) {
+            self.discard = Some(Discard::NoBarcode);
+        }
+    }
+    fn offsets(&mut self) -> [&mut usize; 5] {
+        [
+            &mut self.n,
+            &mut self.r,
+            &mut self.o,
+            &mut self.q,
+            &mut self.e,
+        ]
+    }
+    fn trim(&mut self, params: &Params, adaptors: &Adaptors, buf: &mut [u8]) {
         let seqlen = self.stop;
-        let (qstart, qstop) =
-            qual_trim(&buf[self.q..self.q + seqlen], 0, cutoff as i32);
+        self.len = seqlen;
+        // old Illumina quality scores, to Phred+33 for trimming and for
+        // the output
+        if params.phred64 {
+            for x in &mut buf[self.q..self.q + seqlen] {
+                *x -= 31;
+            }
+        }
+        let qual = &buf[self.q..self.q + seqlen];
+        let (qstart, qstop) = params.qual_trimmer.trim(qual);
+        self.q30 = count_q30(qual);
+        // print each trimming decision for one read, to debug params
+        let explain = params.explain.as_ref().is_some_and(|x| {
+            let name = &buf[self.n + 1..self.r - 1];
+            pair_name(name) == pair_name(x)
+        });
+        if explain {
+            let name = &buf[self.n + 1..self.r - 1];
+            eprintln!("explain: {}", name.escape_ascii());
+            eprintln!("  length: {}", seqlen);
+            eprintln!("  quality trim: keep {}..{}", qstart, qstop);
+        }
+        // fixed construct at the 5' end
+        let pstop = match_prefix(
+            &params.prefix,
+            &buf[self.r..self.r + seqlen],
+            params.prefix_mm,
+        );
         // consecutive N values at both ends
-        let (nstart, nstop) = trim_n_ends(&buf[self.r..self.r + seqlen]);
-        // so no N or low qual bases can interfere with adaptor
-        self.stop = min(qstop, nstop);
-        // find the adaptor at the 3' end
-        let adaptor_start =
-            kmp(adaptor, &sp, &buf[self.r..self.r + seqlen], self.stop);
+        let (nstart, nstop) = match params.trim_n {
+            true => trim_n_ends(&buf[self.r..self.r + seqlen], params.n_window),
+            false => (0, seqlen),
+        };
+        // so no N or low qual bases can interfere with adaptor, unless
+        // low qual bases are masked and must stay in the read
+        self.stop = match params.mask_quality {
+            true => nstop,
+            false => min(qstop, nstop),
+        };
+        // where both apply, bases are counted as cut for quality
+        let q3 = if params.mask_quality { seqlen } else { qstop };
+        self.cut_quality = seqlen - q3;
+        self.cut_n = q3 - self.stop;
+        if explain {
+            eprintln!("  prefix: {} bases", pstop);
+            eprintln!("  N trim: keep {}..{}", nstart, nstop);
+            eprintln!("  search for adaptor before {}", self.stop);
+        }
+        // find the adaptor at the 3' end, but not in the first bases;
+        // with more than one, the best match: the fewest mismatches,
+        // then the longest, then the one that starts first
+        let skip = min(params.ignore_first, self.stop);
+        let read = &buf[self.r + skip..self.r + seqlen];
+        // an adaptor in the read comment is used instead of the others
+        let name = &buf[self.n + 1..self.r - 1];
+        let tagged = params
+            .adaptor_tag
+            .as_ref()
+            .and_then(|tag| comment_field(name, tag));
+        self.tagged = tagged.is_some();
+        let adaptor_start = match tagged {
+            Some(seq) => TAGGED.with(|x| {
+                let mut x = x.borrow_mut();
+                let same = x.as_ref().is_some_and(|a| {
+                    a.seq.eq_ignore_ascii_case(seq)
+                        && a.stringency == adaptors.stringency
+                });
+                if !same {
+                    *x = Some(Adaptor {
+                        max_err: params.max_errors,
+                        stringency: adaptors.stringency,
+                        ..Adaptor::new(&seq.to_ascii_uppercase())
+                    });
+                }
+                let a = x.as_ref().unwrap();
+                skip + a.find(read, self.stop - skip)
+            }),
+            None => {
+                let m = self.stop - skip;
+                adaptors
+                    .back
+                    .iter()
+                    .map(|a| (a, a.find(read, m)))
+                    .filter(|&(_, i)| i < m)
+                    .min_by_key(|&(a, i)| {
+                        let (mm, len) = a.score(&read[..m], i);
+                        (mm, Reverse(len), i)
+                    })
+                    .map_or(self.stop, |(_, i)| skip + i)
+            }
+        };
+        if explain {
+            eprintln!("  skip first {} bases for adaptor", skip);
+            if let Some(seq) = tagged {
+                let x = String::from_utf8_lossy(seq);
+                eprintln!("  adaptor from comment: {}", x);
+            }
+            for a in adaptors.back.iter().filter(|_| tagged.is_none()) {
+                let i = skip + a.find(read, self.stop - skip);
+                let x = String::from_utf8_lossy(&a.seq);
+                eprintln!("  adaptor {}: {}", x, explain_pos(i, self.stop));
+            }
+        }
+        // a homopolymer across the start of the adaptor makes the exact
+        // position unclear, so move it to one edge of the homopolymer
+        let adaptor_start = match params.hp_edge {
+            Some(edge) if adaptor_start < self.stop => {
+                let read = &buf[self.r..self.r + self.stop];
+                homopolymer_edge(read, adaptor_start, edge)
+            }
+            _ => adaptor_start,
+        };
+        if explain && params.hp_edge.is_some() {
+            eprintln!("  homopolymer edge: adaptor at {}", adaptor_start);
+        }
+        self.adaptor = adaptor_start < self.stop;
+        self.dimer = self.adaptor && adaptor_start <= DIMER_MAX_INSERT;
+        self.cut_adaptor = self.stop - min(self.stop, adaptor_start);
         self.stop = min(self.stop, adaptor_start);
-        let (_, nstop) = trim_n_ends(&buf[self.r..self.r + self.stop]);
+        let (_, nstop) = match params.trim_n {
+            true => {
+                trim_n_ends(&buf[self.r..self.r + self.stop], params.n_window)
+            }
+            false => (0, self.stop),
+        };
+        self.cut_n += self.stop - min(self.stop, nstop);
         self.stop = min(self.stop, nstop);
-        self.start = min(max(qstart, nstart), self.stop);
+        if explain {
+            eprintln!("  after adaptor and N trim: stop at {}", self.stop);
+        }
+        // an amplicon shorter than the read, so the opposite primer is
+        // read through to before the adaptor
+        if self.adaptor && !adaptors.primers_rc.is_empty() {
+            let read = &buf[self.r..self.r + self.stop];
+            let i = adaptors.primer_start(read);
+            self.primer = i < self.stop;
+            self.stop = i;
+            if explain {
+                eprintln!("  primer read-through: stop at {}", i);
+            }
+        }
+        // a homopolymer tail, which is often just before the adaptor
+        if let Some((base, min_run)) = params.poly_x {
+            let read = &buf[self.r..self.r + self.stop];
+            let i = poly_x_start(read, base, min_run);
+            self.poly_x = i < self.stop;
+            self.stop = i;
+            if explain {
+                eprintln!("  poly-{} tail: stop at {}", base as char, i);
+            }
+        }
+        // linked adaptors, keeping only what is between them
+        let mut lstart = 0;
+        let linked = adaptors.find_linked(&buf[self.r..self.r + self.stop]);
+        if let Some((i, j)) = linked {
+            (lstart, self.stop) = (i, j);
+        }
+        self.linked = linked.is_some();
+        // 5' adaptors and everything before them
+        let fstop = adaptors
+            .front
+            .iter()
+            .map(|a| a.front_end(&buf[self.r..self.r + self.stop]))
+            .max()
+            .unwrap_or(0);
+        self.front_adaptor = fstop > 0;
+        let fstop = max(fstop, lstart);
+        let qtrim = if params.mask_quality { 0 } else { qstart };
+        self.start = min(max(max(qtrim, nstart), max(pstop, fstop)), self.stop);
+        self.cut_quality += min(qtrim, self.stop);
+        self.cut_n +=
+            min(max(qtrim, nstart), self.stop) - min(qtrim, self.stop);
+        if explain {
+            match linked {
+                Some((i, j)) => {
+                    eprintln!("  linked adaptors: keep {}..{}", i, j)
+                }
+                None => eprintln!("  linked adaptors: none"),
+            }
+            eprintln!("  5' adaptors: remove {} bases", fstop);
+            eprintln!("  keep {}..{}", self.start, self.stop);
+        }
+        // template-switch oligo inside the read
+        if let Some(action) = params.invasion {
+            let read = &buf[self.r..self.r + self.stop];
+            let tso = &params.prefix;
+            if let Some(i) =
+                find_strand_invasion(tso, read, pstop, params.prefix_mm)
+            {
+                self.invasion = true;
+                match action {
+                    Invasion::Discard => {
+                        self.discard = Some(Discard::StrandInvasion)
+                    }
+                    Invasion::Trim => self.stop = max(i, self.start),
+                }
+            }
+        }
+        self.flipped = needs_flip(
+            &params.orient,
+            &buf[self.r + self.start..self.r + self.stop],
+        );
+
+        let r_sz = self.stop - self.start;
+        if self.discard.is_none() && r_sz < params.min_length {
+            self.discard = Some(Discard::TooShort);
+        }
+        // below 1 the limit is a fraction of the bases kept
+        if let (None, Some(max_n)) = (self.discard, params.max_n) {
+            let read = &buf[self.r + self.start..self.r + self.stop];
+            let n = read.iter().filter(|&&x| x == b'N').count();
+            let limit = match max_n < 1.0 {
+                true => max_n * r_sz as f64,
+                false => max_n,
+            };
+            if n as f64 > limit {
+                self.discard = Some(Discard::TooManyN);
+            }
+        }
+        if let (None, Some(max_ee)) = (self.discard, params.max_ee) {
+            let qual = &buf[self.q + self.start..self.q + self.stop];
+            if expected_errors(qual) > max_ee {
+                self.discard = Some(Discard::TooManyErrors);
+            }
+        }
+        if self.discard.is_none() && params.min_complexity > 0.0 {
+            let read = &buf[self.r + self.start..self.r + self.stop];
+            if complexity(read) < params.min_complexity {
+                self.discard = Some(Discard::LowComplexity);
+            }
+        }
+        self.q30_kept =
+            count_q30(&buf[self.q + self.start..self.q + self.stop]);
+        if explain {
+            eprintln!("  strand invasion: {}", self.invasion);
+            eprintln!("  flipped: {}", self.flipped);
+            match self.discard {
+                Some(d) => eprintln!("  discarded: {}", d.name()),
+                None => eprintln!("  kept: {} bases", r_sz),
+            }
+        }
+
+        // leave the record as it is, but keep the trimmed ends
+        if params.passthrough {
+            return;
+        }
 
         /* ADS: Removing the comments in the next two lines breaks up
          * this function, which would allow the work to be done in two
@@ -215,137 +1123,3494 @@ impl FQRec {
         // }
         // fn compress(&mut self, buf: &Vec<u8>) {
 
-        let b = buf.as_ptr() as *mut u8;
-        let r_sz = self.stop - self.start;
-        unsafe {
-            ptr::copy(b.add(self.r + self.start), b.add(self.r), r_sz);
-            *b.add(self.r + r_sz) = b'\n';
-        }
+        let read = self.r + self.start..self.r + self.stop;
+        buf.copy_within(read, self.r);
+        buf[self.r + r_sz] = b'\n';
         let o = self.r + r_sz + 1;
         let o_sz = 2; // self.q - self.o; /* removing "header" after "+" */
-        unsafe {
-            // removing the "header" after the "+"
-            *b.add(o) = b'+';
-            *b.add(o + 1) = b'\n';
-            /* ADS: the code above simulates the code below, since the
-             * second header line in a record is kept empty in our
-             * output anyway.
-             */
-            // ptr::copy(b.add(self.o), b.add(o), o_sz);
-            // assert!(*b.add(o + o_sz - 1) == b'\n');
-            // *b.add(o + o_sz - 1) == b'\n');
-        }
+                      // removing the "header" after the "+"
+        buf[o] = b'+';
+        buf[o + 1] = b'\n';
+        /* ADS: the code above simulates the code below, since the
+         * second header line in a record is kept empty in our output
+         * anyway.
+         */
+        // buf.copy_within(self.o..self.o + o_sz, o);
+        // assert!(buf[o + o_sz - 1] == b'\n');
         self.o = o;
         let q = self.o + o_sz;
-        unsafe {
-            ptr::copy(b.add(self.q + self.start), b.add(q), r_sz);
-            *b.add(q + r_sz) = b'\n';
-        }
+        let qual = self.q + self.start..self.q + self.stop;
+        buf.copy_within(qual, q);
+        buf[q + r_sz] = b'\n';
         self.q = q;
         self.e = self.q + r_sz + 1;
 
+        // low quality bases at the ends become N instead of trimmed
+        if params.mask_quality {
+            for i in self.start..self.stop {
+                if i < qstart || i >= qstop {
+                    buf[self.r + i - self.start] = b'N';
+                }
+            }
+        }
+
+        // put the read in the orientation of the signature
+        if self.flipped {
+            let (seq, qual) = buf.split_at_mut(self.q);
+            reverse_complement(
+                &mut seq[self.r..self.r + r_sz],
+                &mut qual[..r_sz],
+            );
+        }
+
+        // adjust quality scores for the output
+        if let Some(table) = &params.recal {
+            for x in &mut buf[self.q..self.q + r_sz] {
+                *x = table[*x as usize];
+            }
+            self.q30_kept = count_q30(&buf[self.q..self.q + r_sz]);
+        }
+
+        // split at a hairpin, with the second strand turned around
+        // so both are in the same orientation
+        self.hairpin =
+            find_hairpin(&params.hairpin, &buf[self.r..self.r + r_sz]);
+        if let Some((_, j)) = self.hairpin {
+            let (seq, qual) = buf.split_at_mut(self.q);
+            reverse_complement(
+                &mut seq[self.r + j..self.r + r_sz],
+                &mut qual[j..r_sz],
+            );
+        }
+
+        // split a concatemer into the copies of its insert, which is
+        // the only allocation for a read, so only if it is split
+        if let Some(backbone) = &adaptors.backbone {
+            let read = &buf[self.r..self.r + r_sz];
+            if backbone.find(read, r_sz) + backbone.seq.len() <= r_sz {
+                self.subreads = find_subreads(backbone, read);
+            }
+        }
+
+        self.trimmed = (self.start, self.stop);
         self.start = 0;
         self.stop = r_sz;
     }
-    fn write<W: Write>(&self, buf: &Vec<u8>, writer: &mut W) {
-        writer.write(&buf[self.n..self.e]).unwrap();
+    fn write<W: Write>(
+        &self,
+        buf: &[u8],
+        writer: &mut W,
+        coords: bool,
+    ) -> std::io::Result<()> {
+        self.write_tagged(buf, writer, coords, &[])
     }
-}
-
-#[inline(always)]
-fn get_next_record(buf: &mut [u8], cursor: &mut usize, filled: usize) -> FQRec {
-    // ADS: here is where we should detect malformed records
-    let n = *cursor;
-    let r = next_line(buf, filled, n);
-    let o = next_line(buf, filled, r);
-    let q = next_line(buf, filled, o);
-    let e = next_line(buf, filled, q);
-    if e != usize::MAX {
-        *cursor = e;
-        assert!(buf[n] == b'@');
+    /// Write the record with `tags` added to the comment of its name.
+    fn write_tagged<W: Write>(
+        &self,
+        buf: &[u8],
+        writer: &mut W,
+        coords: bool,
+        tags: &[u8],
+    ) -> std::io::Result<()> {
+        // where the part written was in the read as it was input, then
+        // the tags
+        let coords = |w: &mut W, from: usize, to: usize| {
+            let (start, len) = (self.trimmed.0, self.len);
+            if coords {
+                write!(w, " trimmed={}-{}/{}", start + from, start + to, len)?;
+            }
+            w.write_all(tags)
+        };
+        // the rest of a record after its name line
+        let rest = |w: &mut W, from: usize, to: usize| {
+            w.write_all(b"\n")?;
+            w.write_all(&buf[self.r + from..self.r + to])?;
+            w.write_all(b"\n+\n")?;
+            w.write_all(&buf[self.q + from..self.q + to])?;
+            w.write_all(b"\n")
+        };
+        // the copies of the insert in a concatemer, numbered after the
+        // read name and before any comment
+        if !self.subreads.is_empty() {
+            let name = &buf[self.n..self.r - 1];
+            let id = name
+                .iter()
+                .position(|&x| x == b' ' || x == b'\t')
+                .unwrap_or(name.len());
+            for (k, &(from, to)) in self.subreads.iter().enumerate() {
+                writer.write_all(&name[..id])?;
+                write!(writer, "_{}", k + 1)?;
+                writer.write_all(&name[id..])?;
+                coords(writer, from, to)?;
+                rest(writer, from, to)?;
+            }
+            return Ok(());
+        }
+        let (i, j) = match self.hairpin {
+            Some(x) => x,
+            None => {
+                writer.write_all(&buf[self.n..self.r - 1])?;
+                coords(writer, 0, self.stop)?;
+                return writer.write_all(&buf[self.r - 1..self.e]);
+            }
+        };
+        // the two strands on each side of the hairpin
+        for (strand, from, to) in
+            [("template", 0, i), ("complement", j, self.stop)]
+        {
+            writer.write_all(&buf[self.n..self.r - 1])?;
+            write!(writer, " strand={}", strand)?;
+            coords(writer, from, to)?;
+            rest(writer, from, to)?;
+        }
+        Ok(())
     }
-    FQRec {
-        n,
-        r,
-        o,
-        q,
-        e,
-        start: 0,
-        stop: if r < o { o - r - 1 } else { 0 },
+    /// The record as it was in the input, for passthrough: with the
+    /// quality scores back in Phred+64 if they were, and with "\r\n"
+    /// line ends if the input had them.
+    fn write_original<W: Write>(
+        &self,
+        buf: &[u8],
+        writer: &mut W,
+        phred64: bool,
+        crlf: bool,
+    ) -> std::io::Result<()> {
+        let end = if crlf { &b"\r\n"[..] } else { &b"\n"[..] };
+        for line in [self.n..self.r - 1, self.r..self.o - 1, self.o..self.q - 1]
+        {
+            writer.write_all(&buf[line])?;
+            writer.write_all(end)?;
+        }
+        let qual = &buf[self.q..self.q + self.len];
+        match phred64 {
+            true => {
+                let mut chunk = [0u8; 256];
+                for x in qual.chunks(chunk.len()) {
+                    let c = &mut chunk[..x.len()];
+                    c.iter_mut().zip(x).for_each(|(c, &q)| *c = q + 31);
+                    writer.write_all(c)?;
+                }
+            }
+            false => writer.write_all(qual)?,
+        }
+        writer.write_all(end)
+    }
+    fn write_discarded<W: Write>(
+        &self,
+        buf: &[u8],
+        writer: &mut W,
+    ) -> std::io::Result<()> {
+        // the name line without its newline, then the reason
+        let reason = self.discard.map_or("", |x| x.name());
+        writer.write_all(&buf[self.n..self.r - 1])?;
+        writeln!(writer, " reason={}", reason)?;
+        writer.write_all(&buf[self.r..self.e])
     }
 }
 
-fn process_reads<R: Read, W: Write>(
-    buffer_size: usize,
-    adaptor: &[u8],
-    reader: &mut R,
-    mut writer: &mut W,
-    cutoff: u8,
-) -> Result<(), Box<dyn Error>> {
-    let sp = kmp_prefix_function(adaptor);
-
-    let mut buf: Vec<u8> = vec![b'\0'; buffer_size];
-    let mut filled = 0usize;
-    let mut cursor = 0usize;
-
-    let mut recs: Vec<FQRec> = Vec::new();
-
-    loop {
-        // move any unused data to start of buffer
-        shift(&mut buf, &mut cursor, &mut filled);
-
-        // read the input to fill the buffer
-        filled += reader.read(&mut buf[filled..])?;
+/// Subsample keeps `target` reads chosen at random from the whole
+/// input, preferring reads with a sequence not seen before. The first
+/// pass over the input only chooses: the unique sequences with the
+/// smallest hashes under the seed, which is a random sample of them,
+/// and if there are too few, duplicates chosen the same way by their
+/// position. The chosen reads are written in a second pass.
+struct Subsample {
+    target: usize,
+    seed: u64,
+    index: usize,                    // reads offered in this pass
+    seen: HashSet<u64>,              // hashes of sequences in the input
+    uniques: BinaryHeap<(u64, u64)>, // smallest (rank, hash) of uniques
+    dups: BinaryHeap<(u64, usize)>,  // smallest (rank, index) of the rest
+    chosen: HashSet<u64>,            // uniques to write
+    chosen_dups: HashSet<usize>,     // duplicates to write
+    second_pass: bool,
+}
 
-        // find the sequenced read records
-        recs.clear(); // keep capacity
-        loop {
-            let fq = get_next_record(&mut buf, &mut cursor, filled);
-            if fq.e == usize::MAX {
-                break;
+impl Subsample {
+    fn new(target: usize, seed: u64) -> Subsample {
+        Subsample {
+            target,
+            seed,
+            index: 0,
+            seen: HashSet::new(),
+            uniques: BinaryHeap::new(),
+            dups: BinaryHeap::new(),
+            chosen: HashSet::new(),
+            chosen_dups: HashSet::new(),
+            second_pass: false,
+        }
+    }
+    fn rank<T: Hash>(&self, x: T) -> u64 {
+        let mut h = DefaultHasher::new();
+        (self.seed, x).hash(&mut h);
+        h.finish()
+    }
+    /// Keep the `target` smallest in a heap with the largest on top.
+    fn offer<T: Ord>(heap: &mut BinaryHeap<T>, x: T, target: usize) {
+        heap.push(x);
+        if heap.len() > target {
+            heap.pop();
+        }
+    }
+    /// Fix the reads chosen in the first pass, to be written in the
+    /// second.
+    fn start_second_pass(&mut self) {
+        self.chosen = self.uniques.drain().map(|(_, h)| h).collect();
+        let n_dups = self.target.saturating_sub(self.chosen.len());
+        let dups = std::mem::take(&mut self.dups).into_sorted_vec();
+        self.chosen_dups = dups.iter().take(n_dups).map(|x| x.1).collect();
+        self.index = 0;
+        self.second_pass = true;
+    }
+    fn keep(&mut self, seq: &[u8]) -> bool {
+        let mut h = DefaultHasher::new();
+        seq.hash(&mut h);
+        let h = h.finish();
+        let i = self.index;
+        self.index += 1;
+        if self.second_pass {
+            // the first copy of a chosen sequence, which is the copy
+            // that was unique in the first pass
+            return self.chosen.remove(&h) || self.chosen_dups.contains(&i);
+        }
+        match self.seen.insert(h) {
+            true => {
+                let x = (self.rank(h), h);
+                Subsample::offer(&mut self.uniques, x, self.target)
+            }
+            false => {
+                let x = (self.rank(i), i);
+                Subsample::offer(&mut self.dups, x, self.target)
             }
-            recs.push(fq);
         }
+        false
+    }
+}
 
-        // find end-points of trimmed reads
-        recs.par_iter_mut()
-            .for_each(|fq_rec| fq_rec.process(&adaptor, &sp, cutoff, &buf));
+/// The lane from an Illumina read name, either the fourth field of
+/// "@instrument:run:flowcell:lane:tile:x:y" or the second field of
+/// the older "@instrument:lane:tile:x:y".
+fn lane_from_name(name: &[u8]) -> Option<&str> {
+    let name = name.split(|&x| x == b' ' || x == b'\t').next()?;
+    let fields = || name.split(|&x| x == b':');
+    let lane = match fields().count() {
+        7 => fields().nth(3)?,
+        5 => fields().nth(1)?,
+        _ => return None,
+    };
+    match !lane.is_empty() && lane.iter().all(|x| x.is_ascii_digit()) {
+        true => std::str::from_utf8(lane).ok(),
+        false => None,
+    }
+}
 
-        /* ADS: could do separately: make record a contiguous chunk */
-        // recs.iter_mut().for_each(|x| x.compress(&buf));
+/// The output file name for one lane, with "_L001" style text added
+/// before the extensions, so "out.fq.gz" becomes "out_L001.fq.gz".
+fn lane_path(output: &str, lane: &str) -> std::path::PathBuf {
+    let path = std::path::Path::new(output);
+    let name = path
+        .file_name()
+        .map_or(output.into(), |x| x.to_string_lossy());
+    let (stem, ext) = name.split_at(name.find('.').unwrap_or(name.len()));
+    path.with_file_name(format!("{}_L{:0>3}{}", stem, lane, ext))
+}
 
-        // write all records to output file
-        recs.iter_mut().for_each(|x| x.write(&mut buf, &mut writer));
+/// Gzip output as one stream, without the BGZF blocks and empty last
+/// block that some tools can't read. htslib writes it with the "g"
+/// mode, which rust_htslib has no way to ask for.
+#[cfg(not(feature = "pure-rust"))]
+struct GzipWriter(*mut htslib::BGZF);
 
-        // exit if previous read hit end of file
-        if filled < buf.len() {
-            break;
+#[cfg(not(feature = "pure-rust"))]
+impl GzipWriter {
+    fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let name = path.as_ref().to_string_lossy().to_string();
+        let c_name = std::ffi::CString::new(name.as_bytes())?;
+        let mode = c"wg".as_ptr();
+        let fp = unsafe { htslib::bgzf_open(c_name.as_ptr(), mode) };
+        if fp.is_null() {
+            return Err(format!("failed to open output: {}", name))?;
         }
+        Ok(GzipWriter(fp))
     }
 
-    Ok(())
+    /// Close the file, which writes the last of the output, with an
+    /// error if that fails, which dropping it can't give.
+    fn finish(mut self) -> std::io::Result<()> {
+        let fp = std::mem::replace(&mut self.0, std::ptr::null_mut());
+        match unsafe { htslib::bgzf_close(fp) } {
+            0 => Ok(()),
+            _ => Err(std::io::Error::other("gzip close failed")),
+        }
+    }
 }
 
-pub fn remove_adaptors(
-    zip: bool,
-    n_threads: u32,
-    buf_sz: usize,
-    adaptor: &[u8],
-    input: &String,
-    output: &String,
-    cutoff: u8,
-) -> Result<(), Box<dyn Error>> {
-    let lvl = match zip {
-        true => CompLvl::Default,
-        false => CompLvl::NoCompression,
+#[cfg(not(feature = "pure-rust"))]
+impl Write for GzipWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let data = buf.as_ptr() as *const std::os::raw::c_void;
+        match unsafe { htslib::bgzf_write(self.0, data, buf.len()) } {
+            n if n < 0 => Err(std::io::Error::other("gzip write failed")),
+            n => Ok(n as usize),
+        }
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match unsafe { htslib::bgzf_flush(self.0) } {
+            0 => Ok(()),
+            _ => Err(std::io::Error::other("gzip flush failed")),
+        }
+    }
+}
+
+#[cfg(not(feature = "pure-rust"))]
+impl Drop for GzipWriter {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            unsafe {
+                htslib::bgzf_close(self.0);
+            }
+        }
+    }
+}
+
+/// How output is compressed, if it is compressed at all.
+#[derive(Clone, Copy)]
+enum Codec {
+    Bgzf {
+        eof_block: bool,
+    },
+    Gzip,
+    #[cfg(feature = "zstd")]
+    Zstd {
+        dict: bool,
+    },
+}
+
+impl Codec {
+    fn new(params: &Params) -> Codec {
+        match (params.gzip, params.zstd) {
+            (true, _) => Codec::Gzip,
+            #[cfg(feature = "zstd")]
+            (_, true) => Codec::Zstd {
+                dict: params.zstd_dict,
+            },
+            _ => Codec::Bgzf {
+                eof_block: !params.no_eof_block,
+            },
+        }
+    }
+}
+
+/// The first bytes of a zstd dictionary.
+#[cfg(feature = "zstd")]
+const ZSTD_DICT_MAGIC: [u8; 4] = [0x37, 0xa4, 0x30, 0xec];
+
+/// zstd output with a dictionary trained on the first of the output,
+/// which is held back until there is enough of it. The dictionary
+/// goes first in the file, in a skippable frame, where `open_input`
+/// finds it; other tools need it given separately. If the training
+/// fails, as it does with too few records, there is no dictionary.
+#[cfg(feature = "zstd")]
+struct ZstdDictWriter {
+    file: Option<BufWriter<Box<dyn Write>>>,
+    sample: Vec<u8>,
+    encoder: Option<zstd::Encoder<'static, BufWriter<Box<dyn Write>>>>,
+}
+
+#[cfg(feature = "zstd")]
+impl ZstdDictWriter {
+    const SAMPLE_SZ: usize = 1 << 20; // output held back to train on
+    const DICT_SZ: usize = 16 * 1024; // well under 1% of the sample
+
+    fn new(file: BufWriter<Box<dyn Write>>) -> ZstdDictWriter {
+        ZstdDictWriter {
+            file: Some(file),
+            sample: Vec::new(),
+            encoder: None,
+        }
+    }
+
+    /// Train on the records held back, each a sample, then write the
+    /// dictionary and compress them with it.
+    fn start(&mut self) -> std::io::Result<()> {
+        let mut file = self.file.take().unwrap();
+        // the sizes of the whole records, with four lines each
+        let mut prev = 0;
+        let sizes: Vec<usize> = self
+            .sample
+            .iter()
+            .enumerate()
+            .filter(|(_, &x)| x == b'\n')
+            .skip(3)
+            .step_by(4)
+            .map(|(i, _)| {
+                let sz = i + 1 - prev;
+                prev = i + 1;
+                sz
+            })
+            .collect();
+        let sample = &self.sample[..prev];
+        let dict = zstd::dict::from_continuous(sample, &sizes, Self::DICT_SZ)
+            .unwrap_or_default();
+        let mut encoder = match dict.is_empty() {
+            true => zstd::Encoder::new(file, 0)?,
+            false => {
+                file.write_all(&ZSTD_SKIPPABLE_MAGIC)?;
+                file.write_all(&(dict.len() as u32).to_le_bytes())?;
+                file.write_all(&dict)?;
+                zstd::Encoder::with_dictionary(file, 0, &dict)?
+            }
+        };
+        encoder.write_all(&self.sample)?;
+        self.sample = Vec::new();
+        self.encoder = Some(encoder);
+        Ok(())
+    }
+
+    fn finish(mut self) -> std::io::Result<()> {
+        if self.encoder.is_none() {
+            self.start()?;
+        }
+        self.encoder.take().unwrap().finish()?.flush()
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Write for ZstdDictWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.encoder.as_mut() {
+            Some(e) => e.write_all(buf)?,
+            None => {
+                self.sample.extend_from_slice(buf);
+                if self.sample.len() >= Self::SAMPLE_SZ {
+                    self.start()?;
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.encoder.as_mut() {
+            Some(e) => e.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// An output file: BGZF, one gzip stream, zstd or plain text. Plain
+/// text is written without htslib, which would still frame it as BGZF.
+enum Output {
+    Bgzf(bgzf::Writer),
+    Gzip(GzipWriter),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Encoder<'static, BufWriter<Box<dyn Write>>>),
+    #[cfg(feature = "zstd")]
+    ZstdDict(ZstdDictWriter),
+    Plain(BufWriter<Box<dyn Write>>),
+}
+
+impl Output {
+    fn new<P: AsRef<Path>>(
+        path: P,
+        lvl: CompLvl,
+        codec: Codec,
+    ) -> Result<Output, Box<dyn Error>> {
+        let file = |path: P| -> Result<_, Box<dyn Error>> {
+            let w: Box<dyn Write> = match path.as_ref().to_str() {
+                Some("-") => Box::new(std::io::stdout()),
+                _ => Box::new(std::fs::File::create(path)?),
+            };
+            Ok(BufWriter::new(w))
+        };
+        Ok(match (codec, lvl) {
+            (_, CompLvl::Uncompressed) => Output::Plain(file(path)?),
+            (Codec::Gzip, _) => Output::Gzip(GzipWriter::from_path(path)?),
+            #[cfg(feature = "zstd")]
+            (Codec::Zstd { dict: false }, _) => {
+                Output::Zstd(zstd::Encoder::new(file(path)?, 0)?)
+            }
+            #[cfg(feature = "zstd")]
+            (Codec::Zstd { dict: true }, _) => {
+                Output::ZstdDict(ZstdDictWriter::new(file(path)?))
+            }
+            (Codec::Bgzf { eof_block }, _) => {
+                let mut w = bgzf::Writer::from_path_with_level(path, lvl)?;
+                // some consumers of a stream don't expect the empty
+                // last block
+                w.set_eof_block(eof_block);
+                Output::Bgzf(w)
+            }
+        })
+    }
+
+    /// Threads only help with BGZF, where blocks are compressed on
+    /// their own.
+    fn set_thread_pool(
+        &mut self,
+        tpool: &ThreadPool,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Output::Bgzf(w) = self {
+            w.set_thread_pool(tpool)?;
+        }
+        Ok(())
+    }
+
+    /// Write the last of the output, with an error if that fails,
+    /// rather than leaving it to be dropped, which can't give one.
+    fn finish(self) -> std::io::Result<()> {
+        match self {
+            Output::Bgzf(w) => w.finish(),
+            Output::Gzip(w) => w.finish(),
+            // the frame is only complete once the encoder is finished
+            #[cfg(feature = "zstd")]
+            Output::Zstd(w) => w.finish()?.flush(),
+            #[cfg(feature = "zstd")]
+            Output::ZstdDict(w) => w.finish(),
+            Output::Plain(mut w) => w.flush(),
+        }
+    }
+}
+
+impl Write for Output {
+    /// All of `buf` is written, since records are written without
+    /// checking how much was taken, and the encoders can take less.
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Output::Bgzf(w) => w.write_all(buf),
+            Output::Gzip(w) => w.write_all(buf),
+            #[cfg(feature = "zstd")]
+            Output::Zstd(w) => w.write_all(buf),
+            #[cfg(feature = "zstd")]
+            Output::ZstdDict(w) => w.write_all(buf),
+            Output::Plain(w) => w.write_all(buf),
+        }?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Output::Bgzf(w) => w.flush(),
+            Output::Gzip(w) => w.flush(),
+            #[cfg(feature = "zstd")]
+            Output::Zstd(w) => w.flush(),
+            #[cfg(feature = "zstd")]
+            Output::ZstdDict(w) => w.flush(),
+            Output::Plain(w) => w.flush(),
+        }
+    }
+}
+
+/// Lanes holds the output files for reads split by lane, which are
+/// opened the first time a read from each lane is seen.
+struct Lanes<'a> {
+    output: &'a str,
+    lvl: CompLvl,
+    codec: Codec,
+    tpool: Option<&'a ThreadPool>,
+    writers: HashMap<String, Output>,
+}
+
+impl<'a> Lanes<'a> {
+    fn writer(&mut self, lane: &str) -> Result<&mut Output, Box<dyn Error>> {
+        if !self.writers.contains_key(lane) {
+            let path = lane_path(self.output, lane);
+            let mut w = Output::new(path, self.lvl, self.codec)?;
+            if let Some(t) = self.tpool {
+                w.set_thread_pool(t)?;
+            }
+            self.writers.insert(lane.to_string(), w);
+        }
+        Ok(self.writers.get_mut(lane).unwrap())
+    }
+
+    fn finish(self) -> std::io::Result<()> {
+        self.writers.into_values().try_for_each(Output::finish)
+    }
+}
+
+#[inline(always)]
+fn get_next_record(buf: &mut [u8], cursor: &mut usize, filled: usize) -> FQRec {
+    // ADS: here is where we should detect malformed records
+    let n = *cursor;
+    let r = next_line(buf, filled, n);
+    let o = next_line(buf, filled, r);
+    let q = next_line(buf, filled, o);
+    let e = next_line(buf, filled, q);
+    if e != usize::MAX {
+        *cursor = e;
+    }
+    FQRec {
+        n,
+        r,
+        o,
+        q,
+        e,
+        start: 0,
+        stop: if r < o { o - r - 1 } else { 0 },
+        discard: None,
+        invasion: false,
+        flipped: false,
+        len: 0,
+        adaptor: false,
+        front_adaptor: false,
+        linked: false,
+        poly_x: false,
+        primer: false,
+        dimer: false,
+        tagged: false,
+        hairpin: None,
+        subreads: Vec::new(),
+        trimmed: (0, 0),
+        insert: None,
+        q30: 0,
+        q30_kept: 0,
+        cut_adaptor: 0,
+        cut_quality: 0,
+        cut_n: 0,
+    }
+}
+
+/// FQInput holds the buffer for one input file and the records that
+/// are complete in the buffer.
+struct FQInput<'a, R: Read> {
+    reader: &'a mut R,
+    buf: Vec<u8>,
+    filled: usize,
+    cursor: usize,
+    recs: Vec<FQRec>,
+    eof: bool,            // the last read reached the end of the input
+    max_sz: usize,        // the buffer can grow up to this size
+    phred64: bool,        // quality scores are Phred+64
+    checked: bool,        // quality scores were checked for Phred+64
+    permissive: bool,     // skip blank lines, any text after "+"
+    blank_lines: usize,   // blank lines skipped
+    plus_mismatch: usize, // "+" lines with text not the read name
+    crlf: Option<bool>,   // lines end with "\r\n", once known
+    bytes: u64,           // bytes read from the input
+}
+
+impl<'a, R: Read> FQInput<'a, R> {
+    fn new(reader: &'a mut R, params: &Params) -> FQInput<'a, R> {
+        FQInput {
+            reader,
+            buf: vec![b'\0'; params.buf_sz],
+            filled: 0,
+            cursor: 0,
+            recs: Vec::new(),
+            eof: false,
+            max_sz: max(params.max_buf_sz, params.buf_sz),
+            phred64: params.phred64,
+            checked: false,
+            permissive: params.permissive,
+            blank_lines: 0,
+            plus_mismatch: 0,
+            crlf: None,
+            bytes: 0,
+        }
+    }
+
+    /// Check the "+" line and that the quality scores are as long as
+    /// the sequence, since the offsets of a record rely on it. Any
+    /// text after the "+" must be the read name, unless permissive.
+    fn check_record(&mut self, x: &FQRec) -> Result<(), Box<dyn Error>> {
+        let name = &self.buf[x.n + 1..x.r - 1];
+        let plus = &self.buf[x.o..x.q - 1];
+        let err = |what: &str| format!("{}: {}", what, name.escape_ascii());
+        if plus.first() != Some(&b'+') {
+            return Err(err("no \"+\" line for record"))?;
+        }
+        if plus.len() > 1 && &plus[1..] != name {
+            match self.permissive {
+                true => self.plus_mismatch += 1,
+                false => return Err(err("\"+\" line not the name for"))?,
+            }
+        }
+        if x.e - x.q - 1 != x.stop {
+            return Err(err("quality and sequence lengths differ for"))?;
+        }
+        Ok(())
+    }
+
+    /// Check that quality scores fit the encoding: with Phred+64 none
+    /// can be below 64, and otherwise the first records must not look
+    /// like Phred+64, with none low enough for Phred+33 and some above
+    /// the highest Phred+33 score from Illumina.
+    fn check_quality(&mut self) -> Result<(), Box<dyn Error>> {
+        const PHRED64_MIN: u8 = 64;
+        const PHRED33_MAX: u8 = b'J'; // 41 in Phred+33
+        if self.recs.is_empty() || (!self.phred64 && self.checked) {
+            return Ok(());
+        }
+        let quals = self.recs.iter().map(|x| &self.buf[x.q..x.q + x.stop]);
+        let (lo, hi) = quals
+            .flatten()
+            .fold((u8::MAX, 0), |(lo, hi), &x| (min(lo, x), max(hi, x)));
+        self.checked = true;
+        if self.phred64 && lo < PHRED64_MIN {
+            return Err(format!(
+                "quality score {:?} is too low for Phred+64",
+                lo as char
+            ))?;
+        }
+        if !self.phred64 && lo >= PHRED64_MIN && hi > PHRED33_MAX {
+            return Err("quality scores look like Phred+64; use --phred64")?;
+        }
+        Ok(())
+    }
+
+    /// Double the size of the buffer, unless it is already as big as
+    /// it can be. Returns true if the buffer grew.
+    fn grow(&mut self) -> bool {
+        let sz = min(2 * self.buf.len(), self.max_sz);
+        let grew = sz > self.buf.len();
+        self.buf.resize(sz, b'\0');
+        grew
+    }
+
+    /// Fill the buffer after any records not yet used and find the
+    /// records in it.
+    fn fill(&mut self) -> Result<(), Box<dyn Error>> {
+        loop {
+            // move any unused data to start of buffer
+            shift(&mut self.buf, &mut self.cursor, &mut self.filled);
+
+            // read the input to fill the buffer, in more than one read
+            // if needed, since a pipe gives what it has so far
+            let from = self.filled.saturating_sub(1);
+            while self.filled < self.buf.len() {
+                let n = self.reader.read(&mut self.buf[self.filled..])?;
+                if n == 0 {
+                    break;
+                }
+                self.filled += n;
+                self.bytes += n as u64;
+            }
+            self.eof = self.filled < self.buf.len();
+
+            // Windows line ends, known from the first line
+            if self.crlf.is_none() {
+                let buf = &self.buf[..self.filled];
+                if let Some(i) = buf.iter().position(|&x| x == b'\n') {
+                    self.crlf = Some(i > 0 && buf[i - 1] == b'\r');
+                }
+            }
+            if self.crlf == Some(true) {
+                strip_cr(&mut self.buf, from, &mut self.filled);
+            }
+
+            // find the sequenced read records
+            self.recs.clear(); // keep capacity
+            loop {
+                // some tools put blank lines between records
+                while self.permissive
+                    && self.cursor < self.filled
+                    && self.buf[self.cursor] == b'\n'
+                {
+                    self.cursor += 1;
+                    self.blank_lines += 1;
+                }
+                let fq = get_next_record(
+                    &mut self.buf,
+                    &mut self.cursor,
+                    self.filled,
+                );
+                if fq.e == usize::MAX {
+                    break;
+                }
+                // names can have any bytes, so escape them to report
+                if self.buf[fq.n] == b'\n' {
+                    return Err("blank line in input; see --permissive")?;
+                }
+                if self.buf[fq.n] != b'@' {
+                    let line = self.buf[fq.n..fq.r - 1].escape_ascii();
+                    return Err(format!("malformed record: {}", line))?;
+                }
+                self.check_record(&fq)?;
+                self.recs.push(fq);
+            }
+
+            // a record too big for the buffer, e.g. with a very long
+            // name line: make the buffer bigger and read more
+            if self.recs.is_empty() && !self.eof {
+                if !self.grow() {
+                    return Err(format!(
+                        "record too big for buffer of max size {}",
+                        self.max_sz
+                    ))?;
+                }
+                continue;
+            }
+            // too few records for the threads to pay off, so the
+            // buffer is holding back the work: a bigger one next time
+            if self.recs.len() < MIN_PARALLEL_RECS && !self.eof {
+                self.grow();
+            }
+            // here, since processing the records can't give errors
+            self.check_quality()?;
+            return Ok(());
+        }
+    }
+
+    /// Keep only the first `n` records, leaving the rest in the
+    /// buffer for the next fill.
+    fn truncate(&mut self, n: usize) {
+        if n < self.recs.len() {
+            self.cursor = self.recs[n].n;
+            self.recs.truncate(n);
+        }
+    }
+}
+
+/// Find end-points of trimmed reads, in one thread if there are too
+/// few reads for the threads to pay off.
+fn process_batch(
+    params: &Params,
+    adaptors: &Adaptors,
+    recs: &mut [FQRec],
+    buf: &mut [u8],
+) {
+    let recs = record_bufs(recs, buf);
+    let process = |(x, rec): RecBuf| x.process(params, adaptors, rec);
+    if recs.len() < MIN_PARALLEL_RECS {
+        recs.into_iter().for_each(process);
+    } else {
+        recs.into_par_iter().for_each(process);
+    }
+}
+
+/// A record with its part of the buffer, which it can change in place
+/// while other threads change theirs.
+type RecBuf<'a> = (&'a mut FQRec, &'a mut [u8]);
+
+/// Each record with its part of the buffer, for records in the order
+/// they are in the buffer.
+fn record_bufs<'a>(
+    recs: &'a mut [FQRec],
+    mut buf: &'a mut [u8],
+) -> Vec<RecBuf<'a>> {
+    let mut done = 0; // where `buf` starts in the whole buffer
+    recs.iter_mut()
+        .map(|x| {
+            let rest = std::mem::take(&mut buf);
+            let (rec, rest) = rest[x.n - done..].split_at_mut(x.e - x.n);
+            (buf, done) = (rest, x.e);
+            (x, rec)
+        })
+        .collect()
+}
+
+/// The insert size of a pair from where its ends overlap, if they do.
+/// The start of each end is looked for in the other, with the second
+/// reverse-complemented, and then the whole overlap must match with
+/// few mismatches. A short insert is read through into the adaptor,
+/// so then the first end starts inside the second.
+fn pair_insert(read1: &[u8], read2: &[u8]) -> Option<usize> {
+    const K: usize = 12; // seed, also the shortest overlap
+    const MAX_MM: usize = 10; // one mismatch allowed in this many bases
+    let (n, m) = (read1.len(), read2.len());
+    if n < K || m < K {
+        return None;
+    }
+    // the second end reverse-complemented, one base at a time so no
+    // copy is made for each pair
+    let rc = |j: usize| complement(read2[m - 1 - j]);
+    let agree = |i: usize, j: usize| {
+        let len = min(n - i, m - j);
+        let mm = (0..len).filter(|&k| read1[i + k] != rc(j + k)).count();
+        mm * MAX_MM <= len
+    };
+    // the second end starts inside the first
+    let seed: [u8; K] = std::array::from_fn(rc);
+    if let Some(i) = read1.windows(K).position(|w| w == seed) {
+        if agree(i, 0) {
+            return Some(i + m);
+        }
+    }
+    // the first end starts inside the second
+    if let Some(i) =
+        (0..=m - K).find(|&i| (0..K).all(|k| rc(i + k) == read1[k]))
+    {
+        if agree(0, i) {
+            return Some(m - i);
+        }
+    }
+    None
+}
+
+/// Like process_batch, but for pairs, with both ends of a pair in the
+/// same task so that decisions for the pair have both ends at hand.
+/// Each task has at least `params.pair_batch` pairs.
+fn process_pair_batch(
+    params: &Params,
+    adaptors: (&Adaptors, &Adaptors),
+    recs: (&mut [FQRec], &mut [FQRec]),
+    bufs: (&mut [u8], &mut [u8]),
+) {
+    let ends = (record_bufs(recs.0, bufs.0), record_bufs(recs.1, bufs.1));
+    let process = |((x, rec1), (y, rec2)): (RecBuf, RecBuf)| {
+        // before trimming, which moves the reads in the buffers, and
+        // only for a report, since it takes time for every pair
+        if params.pair_inserts {
+            let (i, j) = (x.r - x.n, y.r - y.n);
+            x.insert = pair_insert(&rec1[i..i + x.stop], &rec2[j..j + y.stop]);
+        }
+        match params.barcode {
+            Some(_) => x.keep_barcode(params, rec1),
+            None => x.process(params, adaptors.0, rec1),
+        }
+        y.process(params, adaptors.1, rec2);
+        // if one end is discarded, so is its mate
+        match (x.discard, y.discard) {
+            (Some(_), None) => y.discard = Some(Discard::MateDiscarded),
+            (None, Some(_)) => x.discard = Some(Discard::MateDiscarded),
+            _ => (),
+        }
+    };
+    if ends.0.len() < MIN_PARALLEL_RECS {
+        ends.0.into_iter().zip(ends.1).for_each(process);
+    } else {
+        ends.0
+            .into_par_iter()
+            .zip(ends.1)
+            .with_min_len(params.pair_batch)
+            .for_each(process);
+    }
+}
+
+/// SAM tags for the raw cell barcode and UMI, with their quality
+/// scores, from the start of a barcode read, to add to its mate.
+fn barcode_tags(
+    tags: &mut Vec<u8>,
+    (cb, umi): (usize, usize),
+    x: &FQRec,
+    buf: &[u8],
+) {
+    let (seq, qual) = (&buf[x.r..], &buf[x.q..]);
+    let fields = [
+        ("CR", &seq[..cb]),
+        ("CY", &qual[..cb]),
+        ("UR", &seq[cb..cb + umi]),
+        ("UY", &qual[cb..cb + umi]),
+    ];
+    tags.clear();
+    for (i, (tag, value)) in fields.into_iter().enumerate() {
+        tags.push(if i == 0 { b' ' } else { b'\t' });
+        tags.extend_from_slice(tag.as_bytes());
+        tags.extend_from_slice(b":Z:");
+        tags.extend_from_slice(value);
+    }
+}
+
+/// Rough composition from the first reads.
+fn screen_batch(
+    params: &Params,
+    stats: &mut Stats,
+    recs: &[FQRec],
+    buf: &[u8],
+) {
+    if let Some(screen) = &params.screen {
+        for x in recs.iter() {
+            if stats.screened == screen.n_reads {
+                break;
+            }
+            stats.screened += 1;
+            let read = &buf[x.r + x.start..x.r + x.stop];
+            if let Some(i) = screen.classify(read) {
+                stats.screen[i].1 += 1;
+            }
+        }
+    }
+}
+
+/// Check the first kept reads for what is left of an adaptor after
+/// trimming: any k-mer of an adaptor in the read. Many of these mean
+/// the adaptor was often missed.
+fn remnant_batch(
+    params: &Params,
+    adaptors: &Adaptors,
+    stats: &mut Stats,
+    recs: &[FQRec],
+    buf: &[u8],
+) {
+    const K: usize = 12; // long enough to rarely be there by chance
+    for x in recs.iter().filter(|x| x.discard.is_none()) {
+        if stats.remnant_checked == params.remnant_reads {
+            break;
+        }
+        stats.remnant_checked += 1;
+        let read = &buf[x.r + x.start..x.r + x.stop];
+        let found = adaptors.back.iter().any(|a| {
+            let k = min(K, a.seq.len());
+            a.seq.windows(k).any(|kmer| {
+                read.windows(k).any(|w| {
+                    kmer.iter().zip(w).all(|(&a, &r)| iupac_match(a, r))
+                })
+            })
+        });
+        stats.remnants += found as usize;
+    }
+}
+
+/// Give the records as they are in the input to any collectors.
+fn collect_in(params: &Params, recs: &[FQRec], buf: &[u8]) {
+    for c in &params.collectors {
+        for x in recs {
+            let (seq, qual) = (x.r..x.r + x.stop, x.q..x.q + x.stop);
+            c.on_record_in(&buf[x.n + 1..x.r - 1], &buf[seq], &buf[qual]);
+        }
+    }
+}
+
+/// Give the trimmed lengths of the records to any collectors.
+fn collect_trim(params: &Params, recs: &[FQRec], buf: &[u8]) {
+    for c in &params.collectors {
+        for x in recs {
+            let name = &buf[x.n + 1..x.r - 1];
+            let discard = x.discard.map(|d| d.name());
+            c.on_trim(name, x.len, x.stop - x.start, discard);
+        }
+    }
+}
+
+/// Give a record as it is written to any collectors.
+fn collect_out(params: &Params, x: &FQRec, buf: &[u8]) {
+    for c in &params.collectors {
+        let (seq, qual) =
+            (x.r + x.start..x.r + x.stop, x.q + x.start..x.q + x.stop);
+        c.on_record_out(&buf[x.n + 1..x.r - 1], &buf[seq], &buf[qual]);
+    }
+}
+
+fn new_stats(params: &Params) -> Stats {
+    let mut stats = Stats {
+        length_bin: params.length_bin,
+        ..Default::default()
+    };
+    if let Some(screen) = &params.screen {
+        stats.screen = screen.names.iter().map(|x| (x.clone(), 0)).collect();
+    }
+    stats
+}
+
+fn process_reads<R: Read, W: Write>(
+    params: &Params,
+    reader: &mut R,
+    mut writer: &mut W,
+    mut discarded: Option<&mut W>,
+    mut subsample: Option<&mut Subsample>,
+    mut lanes: Option<&mut Lanes>,
+    mut monitor: Option<&mut Monitor>,
+) -> Result<Stats, Box<dyn Error>> {
+    let adaptors = Adaptors::new(&params.adaptors, params, 0);
+
+    let mut input = FQInput::new(reader, params);
+    let mut stats = new_stats(params);
+    // a second pass for subsampling has already been seen
+    let first_pass = !subsample.as_ref().is_some_and(|s| s.second_pass);
+    let mut first_batch = first_pass;
+
+    let mut to_skip = params.skip_reads;
+
+    loop {
+        input.fill()?;
+        // reads done in an earlier run
+        let n_skip = min(to_skip, input.recs.len());
+        input.recs.drain(..n_skip);
+        to_skip -= n_skip;
+        stats.skipped += n_skip;
+        let crlf = input.crlf == Some(true);
+        let (recs, buf) = (&mut input.recs, &mut input.buf);
+        if let Some(m) = monitor.as_mut() {
+            m.lap(Stage::Read);
+        }
+
+        if first_pass {
+            collect_in(params, recs, buf);
+        }
+        process_batch(params, &adaptors, recs, buf);
+        if first_pass {
+            collect_trim(params, recs, buf);
+        }
+        stats.update(recs);
+        screen_batch(params, &mut stats, recs, buf);
+        remnant_batch(params, &adaptors, &mut stats, recs, buf);
+        if let Some(m) = monitor.as_mut() {
+            m.lap(Stage::Trim);
+        }
+
+        /* ADS: could do separately: make record a contiguous chunk */
+        // recs.iter_mut().for_each(|x| x.compress(&buf));
+
+        // write all records to output file, and discarded records to
+        // their own file if requested
+        for x in recs.iter() {
+            if params.passthrough {
+                x.write_original(buf, writer, params.phred64, crlf)?;
+                collect_out(params, x, buf);
+                stats.written += 1;
+                continue;
+            }
+            match (x.discard, discarded.as_mut()) {
+                (None, _) => {
+                    let seq = &buf[x.r..x.r + x.stop];
+                    if !subsample.as_mut().is_none_or(|s| s.keep(seq)) {
+                        continue;
+                    }
+                    stats.written += match x.subreads.len() {
+                        0 => 1 + x.hairpin.is_some() as usize,
+                        n => n,
+                    };
+                    collect_out(params, x, buf);
+                    // reads without a lane go to the main output
+                    let lane = lanes.as_mut().and_then(|l| {
+                        lane_from_name(&buf[x.n + 1..x.r - 1]).map(|x| (l, x))
+                    });
+                    match lane {
+                        Some((l, lane)) => {
+                            x.write(buf, l.writer(lane)?, params.emit_coords)?
+                        }
+                        None => {
+                            x.write(buf, &mut writer, params.emit_coords)?
+                        }
+                    }
+                }
+                (Some(_), Some(d)) => x.write_discarded(buf, d)?,
+                (Some(_), None) => (),
+            }
+        }
+        if let Some(m) = monitor.as_mut() {
+            m.lap(Stage::Write);
+            m.update(&[&stats], input.bytes);
+        }
+        if let (true, Some(f)) = (first_batch, params.after_first_batch) {
+            f(stats.reads);
+        }
+        first_batch = false;
+
+        // exit if previous read hit end of file
+        if input.eof {
+            break;
+        }
+        // stop between batches, so the output has only whole records
+        if params.deadline.is_some_and(|t| Instant::now() >= t) {
+            stats.stopped = true;
+            break;
+        }
+    }
+    if let Some(m) = monitor {
+        m.finish(&[&stats]);
+    }
+
+    stats.buf_sz = input.buf.len();
+    stats.blank_lines = input.blank_lines;
+    stats.plus_mismatch = input.plus_mismatch;
+
+    Ok(stats)
+}
+
+/// The name of a read up to the first space, without any "/1" or
+/// "/2" at the end, which should be the same for both ends of a pair.
+fn pair_name(name: &[u8]) -> &[u8] {
+    let name = name.split(|&x| x == b' ' || x == b'\t').next().unwrap();
+    match name {
+        [x @ .., b'/', b'1' | b'2'] => x,
+        _ => name,
+    }
+}
+
+/// The outputs for paired reads: one for each end, and if asked for,
+/// one for discarded pairs and one for each end kept without its mate.
+struct PairOutputs<'a, W: Write> {
+    ends: (&'a mut W, &'a mut W),
+    discarded: Option<&'a mut W>,
+    unpaired: (Option<&'a mut W>, Option<&'a mut W>),
+}
+
+/// The records of an input with interleaved pairs, for each end to
+/// read on its own: each record goes to the end it belongs to, and
+/// waits there until that end reads it.
+struct Deinterleave<R: BufRead> {
+    reader: R,
+    ends: [VecDeque<u8>; 2],
+    next: usize,   // the end of the next record in the input
+    line: Vec<u8>, // reused for each line
+}
+
+impl<R: BufRead> Deinterleave<R> {
+    fn new(reader: R) -> Deinterleave<R> {
+        Deinterleave {
+            reader,
+            ends: [VecDeque::new(), VecDeque::new()],
+            next: 0,
+            line: Vec::new(),
+        }
+    }
+
+    /// Move the next record to its end, or false at the end of input.
+    fn next_record(&mut self) -> std::io::Result<bool> {
+        let mut n = 0;
+        for _ in 0..4 {
+            self.line.clear();
+            n += self.reader.read_until(b'\n', &mut self.line)?;
+            self.ends[self.next].extend(&self.line);
+        }
+        self.next ^= 1;
+        Ok(n > 0)
+    }
+}
+
+/// One end of interleaved pairs, read through the shared input.
+struct InterleavedEnd<'a, R: BufRead> {
+    pairs: &'a RefCell<Deinterleave<R>>,
+    end: usize,
+}
+
+impl<R: BufRead> Read for InterleavedEnd<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut pairs = self.pairs.borrow_mut();
+        while pairs.ends[self.end].is_empty() && pairs.next_record()? {}
+        pairs.ends[self.end].read(buf)
+    }
+}
+
+/// An output shared by both ends, for pairs written interleaved,
+/// since process_pairs writes the two ends of a pair one after the
+/// other.
+struct SharedWriter<'a, W: Write>(&'a RefCell<W>);
+
+impl<W: Write> Write for SharedWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+/// Like process_reads, but for the two ends of paired reads, which
+/// are read in lock-step. A pair is kept or discarded as a whole, so
+/// the two outputs stay in sync, and discarded pairs are written with
+/// one end after the other.
+fn process_pairs<R: Read, W: Write>(
+    params: &Params,
+    readers: (&mut R, &mut R),
+    outputs: PairOutputs<W>,
+    mut subsample: Option<&mut Subsample>,
+    mut lanes: Option<(&mut Lanes, &mut Lanes)>,
+    mut monitor: Option<&mut Monitor>,
+) -> Result<(Stats, Stats, PairStats), Box<dyn Error>> {
+    // the second end may have its own adaptors
+    let adaptors1 = Adaptors::new(&params.adaptors, params, 0);
+    let adaptors2 = match &params.adaptors2 {
+        Some(x) => Adaptors::new(x, params, 1),
+        None => Adaptors::new(&params.adaptors, params, 1),
+    };
+
+    let mut input1 = FQInput::new(readers.0, params);
+    let mut input2 = FQInput::new(readers.1, params);
+    let (mut stats1, mut stats2) = (new_stats(params), new_stats(params));
+    let mut pair_stats = PairStats::default();
+    let PairOutputs {
+        ends: (writer1, writer2),
+        mut discarded,
+        mut unpaired,
+    } = outputs;
+    // a second pass for subsampling has already been seen
+    let first_pass = !subsample.as_ref().is_some_and(|s| s.second_pass);
+    let mut first_batch = first_pass;
+    let mut to_skip = params.skip_reads;
+    let mut seq = Vec::new(); // both ends of a pair, reused
+    let mut tags = Vec::new(); // cell barcode and UMI, reused
+
+    loop {
+        input1.fill()?;
+        input2.fill()?;
+
+        // only whole pairs; the rest wait for the next fill
+        let n_pairs = min(input1.recs.len(), input2.recs.len());
+        if n_pairs == 0 {
+            if input1.recs.is_empty() && input2.recs.is_empty() {
+                break;
+            }
+            Err("paired inputs have different numbers of reads")?;
+        }
+        input1.truncate(n_pairs);
+        input2.truncate(n_pairs);
+        // pairs done in an earlier run
+        let n_skip = min(to_skip, n_pairs);
+        input1.recs.drain(..n_skip);
+        input2.recs.drain(..n_skip);
+        to_skip -= n_skip;
+        stats1.skipped += n_skip;
+        stats2.skipped += n_skip;
+        let crlf = (input1.crlf == Some(true), input2.crlf == Some(true));
+        let (recs1, buf1) = (&mut input1.recs, &mut input1.buf);
+        let (recs2, buf2) = (&mut input2.recs, &mut input2.buf);
+        if let Some(m) = monitor.as_mut() {
+            m.lap(Stage::Read);
+        }
+
+        for (x, y) in recs1.iter().zip(recs2.iter()) {
+            let (a, b) = (&buf1[x.n + 1..x.r - 1], &buf2[y.n + 1..y.r - 1]);
+            if pair_name(a) != pair_name(b) {
+                Err(format!(
+                    "paired reads with different names: {} and {}",
+                    a.escape_ascii(),
+                    b.escape_ascii()
+                ))?;
+            }
+        }
+
+        if first_pass {
+            collect_in(params, recs1, buf1);
+            collect_in(params, recs2, buf2);
+        }
+        process_pair_batch(
+            params,
+            (&adaptors1, &adaptors2),
+            (recs1, recs2),
+            (buf1, buf2),
+        );
+        if first_pass {
+            collect_trim(params, recs1, buf1);
+            collect_trim(params, recs2, buf2);
+        }
+
+        stats1.update(recs1);
+        stats2.update(recs2);
+        pair_stats.update(recs1, recs2);
+        screen_batch(params, &mut stats1, recs1, buf1);
+        screen_batch(params, &mut stats2, recs2, buf2);
+        remnant_batch(params, &adaptors1, &mut stats1, recs1, buf1);
+        remnant_batch(params, &adaptors2, &mut stats2, recs2, buf2);
+        if let Some(m) = monitor.as_mut() {
+            m.lap(Stage::Trim);
+        }
+
+        let mate = Some(Discard::MateDiscarded);
+        for (x, y) in recs1.iter().zip(recs2.iter()) {
+            if let (None, Some(b)) = (x.discard, params.barcode) {
+                barcode_tags(&mut tags, b, x, buf1);
+            }
+            if params.passthrough {
+                x.write_original(buf1, writer1, params.phred64, crlf.0)?;
+                y.write_original(buf2, writer2, params.phred64, crlf.1)?;
+                collect_out(params, x, buf1);
+                collect_out(params, y, buf2);
+                stats1.written += 1;
+                stats2.written += 1;
+                continue;
+            }
+            match (x.discard, discarded.as_mut()) {
+                (None, _) => {
+                    // a pair is a duplicate only if both ends are, so
+                    // hash them together, with a separator so that a
+                    // base can't move from one end to the other
+                    let keep = subsample.as_mut().is_none_or(|s| {
+                        seq.clear();
+                        seq.extend_from_slice(&buf1[x.r..x.r + x.stop]);
+                        seq.push(b'\n');
+                        seq.extend_from_slice(&buf2[y.r..y.r + y.stop]);
+                        s.keep(&seq)
+                    });
+                    if !keep {
+                        continue;
+                    }
+                    stats1.written += 1;
+                    stats2.written += 1;
+                    collect_out(params, x, buf1);
+                    collect_out(params, y, buf2);
+                    // both ends go with the lane of the first
+                    let lane = lanes.as_mut().and_then(|l| {
+                        lane_from_name(&buf1[x.n + 1..x.r - 1]).map(|x| (l, x))
+                    });
+                    match lane {
+                        Some(((l1, l2), lane)) => {
+                            x.write(
+                                buf1,
+                                l1.writer(lane)?,
+                                params.emit_coords,
+                            )?;
+                            y.write_tagged(
+                                buf2,
+                                l2.writer(lane)?,
+                                params.emit_coords,
+                                &tags,
+                            )?;
+                        }
+                        None => {
+                            x.write(buf1, writer1, params.emit_coords)?;
+                            y.write_tagged(
+                                buf2,
+                                writer2,
+                                params.emit_coords,
+                                &tags,
+                            )?;
+                        }
+                    }
+                }
+                (Some(_), Some(d)) => {
+                    if !(x.discard == mate && unpaired.0.is_some()) {
+                        x.write_discarded(buf1, d)?;
+                    }
+                    if !(y.discard == mate && unpaired.1.is_some()) {
+                        y.write_discarded(buf2, d)?;
+                    }
+                }
+                (Some(_), None) => (),
+            }
+            // an end discarded only for its mate goes to its own output,
+            // which takes precedence over the output for discarded reads
+            if let (true, Some(u)) = (x.discard == mate, unpaired.0.as_mut()) {
+                x.write(buf1, u, params.emit_coords)?;
+                stats1.unpaired += 1;
+            }
+            if let (true, Some(u)) = (y.discard == mate, unpaired.1.as_mut()) {
+                y.write(buf2, u, params.emit_coords)?;
+                stats2.unpaired += 1;
+            }
+        }
+        if let Some(m) = monitor.as_mut() {
+            m.lap(Stage::Write);
+            m.update(&[&stats1, &stats2], input1.bytes + input2.bytes);
+        }
+        if let (true, Some(f)) = (first_batch, params.after_first_batch) {
+            f(stats1.reads);
+        }
+        first_batch = false;
+
+        // stop between batches, so the outputs have only whole records
+        if params.deadline.is_some_and(|t| Instant::now() >= t) {
+            stats1.stopped = true;
+            stats2.stopped = true;
+            break;
+        }
+    }
+    if let Some(m) = monitor {
+        m.finish(&[&stats1, &stats2]);
+    }
+
+    stats1.buf_sz = input1.buf.len();
+    stats2.buf_sz = input2.buf.len();
+    stats1.blank_lines = input1.blank_lines;
+    stats2.blank_lines = input2.blank_lines;
+    stats1.plus_mismatch = input1.plus_mismatch;
+    stats2.plus_mismatch = input2.plus_mismatch;
+
+    Ok((stats1, stats2, pair_stats))
+}
+
+/// Which edge of a homopolymer to move an adaptor start to.
+#[derive(Clone, Copy, Debug)]
+pub enum HomopolymerEdge {
+    Extend,
+    Retract,
+}
+
+/// What to do with reads that have a strand invasion artifact.
+#[derive(Clone, Copy, Debug)]
+pub enum Invasion {
+    Discard,
+    Trim,
+}
+
+/// Counts that summarize the reads in one input file. Reads and
+/// bases "kept" are those remaining after trimming and filtering.
+#[derive(Default)]
+pub struct Stats {
+    pub reads: usize,           // records in the input
+    pub reads_kept: usize,      // records not discarded
+    pub bases: usize,           // bases in the input
+    pub bases_kept: usize,      // bases in records not discarded
+    pub with_adaptor: usize,    // reads where the adaptor was found
+    pub with_front: usize,      // reads where a 5' adaptor was found
+    pub with_linked: usize,     // reads where linked adaptors were found
+    pub with_poly_x: usize,     // reads with a poly-X tail
+    pub with_primer: usize,     // reads with primer read-through
+    pub dimers: usize,          // reads with adaptor at the start
+    pub strand_invasion: usize, // strand invasion artifacts found
+    pub flipped: usize,         // reads reverse-complemented
+    pub hairpins: usize,        // reads split at a hairpin
+    pub concatemers: usize,     // reads split at a concatemer backbone
+    pub subreads: usize,        // parts of concatemers written
+    pub tagged: usize,          // reads with an adaptor in the comment
+    pub discarded: BTreeMap<&'static str, usize>, // counts by reason
+    pub length_bin: usize,      // width of read length bins, 0 for none
+    pub by_length: BTreeMap<usize, LengthBin>, // counts by length bin
+    pub screened: usize,        // reads checked against the screen
+    pub screen: Vec<(String, usize)>, // reads assigned to each reference
+    pub lengths: Vec<(usize, usize)>, // reads by length, before and after
+    pub buf_sz: usize,          // size of the input buffer at the end
+    pub q30_bases: usize,       // bases with quality at least 30
+    pub q30_bases_kept: usize,  // the same in records not discarded
+    pub cut_adaptor: usize,     // bases trimmed for the 3' adaptor
+    pub cut_quality: usize,     // bases trimmed for low quality
+    pub cut_n: usize,           // bases trimmed for N at the ends
+    pub cut_other: usize,       // bases trimmed for any other reason
+    pub remnant_checked: usize, // kept reads checked for adaptor remnants
+    pub remnants: usize,        // reads with adaptor remnants
+    pub written: usize,         // records written to the output
+    pub blank_lines: usize,     // blank lines skipped in the input
+    pub plus_mismatch: usize,   // "+" lines with text not the read name
+    pub skipped: usize,         // reads skipped at the start
+    pub unpaired: usize,        // reads written without their mate
+    pub stopped: bool,          // stopped at the deadline, before the end
+}
+
+/// Counts for pairs as a whole, for what the two ends of a pair can
+/// say together. Insert sizes are known only for pairs with ends that
+/// overlap, and are only looked for with `Params::pair_inserts`.
+#[derive(Default)]
+pub struct PairStats {
+    pub pairs: usize,          // pairs in the input
+    pub pairs_kept: usize,     // pairs with neither end discarded
+    pub discarded_one: usize,  // pairs discarded for one end only
+    pub discarded_both: usize, // pairs with both ends discarded
+    pub overlapping: usize,    // pairs with ends that overlap
+    pub inserts: Vec<usize>,   // overlapping pairs by insert size
+}
+
+impl PairStats {
+    fn update(&mut self, recs1: &[FQRec], recs2: &[FQRec]) {
+        let mate = Some(Discard::MateDiscarded);
+        for (x, y) in recs1.iter().zip(recs2) {
+            self.pairs += 1;
+            match (x.discard, y.discard) {
+                (None, _) => self.pairs_kept += 1,
+                _ if x.discard == mate || y.discard == mate => {
+                    self.discarded_one += 1
+                }
+                _ => self.discarded_both += 1,
+            }
+            if let Some(i) = x.insert {
+                self.overlapping += 1;
+                if self.inserts.len() <= i {
+                    self.inserts.resize(i + 1, 0);
+                }
+                self.inserts[i] += 1;
+            }
+        }
+    }
+
+    /// The median insert size of overlapping pairs.
+    fn median_insert(&self) -> Option<usize> {
+        let mut seen = 0;
+        self.inserts.iter().position(|&x| {
+            seen += x;
+            2 * seen >= self.overlapping
+        })
+    }
+}
+
+impl std::fmt::Display for PairStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let frac = |x: usize| x as f64 / max(self.pairs, 1) as f64;
+        writeln!(f, "pairs: {}", self.pairs)?;
+        writeln!(f, "pairs kept: {}", self.pairs_kept)?;
+        writeln!(f, "pairs discarded for one end: {}", self.discarded_one)?;
+        writeln!(f, "pairs discarded for both: {}", self.discarded_both)?;
+        writeln!(
+            f,
+            "pairs overlapping: {} ({:.4})",
+            self.overlapping,
+            frac(self.overlapping)
+        )?;
+        if let Some(x) = self.median_insert() {
+            let total: usize =
+                self.inserts.iter().enumerate().map(|(i, n)| i * n).sum();
+            let mean = total as f64 / self.overlapping as f64;
+            writeln!(f, "insert size median: {}", x)?;
+            writeln!(f, "insert size mean: {:.1}", mean)?;
+        }
+        Ok(())
+    }
+}
+
+/// Counts for reads with original length in one bin.
+#[derive(Default)]
+pub struct LengthBin {
+    pub reads: usize,         // reads in the bin
+    pub with_adaptor: usize,  // reads where the adaptor was found
+    pub bases: usize,         // bases before trimming
+    pub bases_trimmed: usize, // bases removed by trimming
+}
+
+impl Stats {
+    fn update(&mut self, recs: &[FQRec]) {
+        for x in recs {
+            self.reads += 1;
+            self.bases += x.len;
+            self.q30_bases += x.q30;
+            self.with_adaptor += x.adaptor as usize;
+            self.with_front += x.front_adaptor as usize;
+            self.with_linked += x.linked as usize;
+            self.with_poly_x += x.poly_x as usize;
+            self.with_primer += x.primer as usize;
+            self.dimers += x.dimer as usize;
+            self.strand_invasion += x.invasion as usize;
+            self.flipped += x.flipped as usize;
+            self.hairpins += x.hairpin.is_some() as usize;
+            self.concatemers += !x.subreads.is_empty() as usize;
+            self.subreads += x.subreads.len();
+            self.tagged += x.tagged as usize;
+            if let Some(i) = x.len.checked_div(self.length_bin) {
+                let bin = self.by_length.entry(i).or_default();
+                bin.reads += 1;
+                bin.with_adaptor += x.adaptor as usize;
+                bin.bases += x.len;
+                bin.bases_trimmed += x.len - (x.stop - x.start);
+            }
+            let kept_len = x.stop - x.start;
+            let cut = x.cut_adaptor + x.cut_quality + x.cut_n;
+            self.cut_adaptor += x.cut_adaptor;
+            self.cut_quality += x.cut_quality;
+            self.cut_n += x.cut_n;
+            self.cut_other += (x.len - kept_len).saturating_sub(cut);
+            let longest = max(x.len, kept_len);
+            if self.lengths.len() <= longest {
+                self.lengths.resize(longest + 1, (0, 0));
+            }
+            self.lengths[x.len].0 += 1;
+            match x.discard {
+                Some(d) => *self.discarded.entry(d.name()).or_insert(0) += 1,
+                None => {
+                    self.reads_kept += 1;
+                    self.bases_kept += kept_len;
+                    self.q30_bases_kept += x.q30_kept;
+                    self.lengths[kept_len].1 += 1;
+                }
+            }
+        }
+    }
+}
+
+impl Stats {
+    /// The value of a named metric, for checking conditions.
+    pub fn metric(&self, name: &str) -> Option<f64> {
+        let rate = |x: usize| x as f64 / max(self.reads, 1) as f64;
+        let discarded: usize = self.discarded.values().sum();
+        Some(match name {
+            "reads" => self.reads as f64,
+            "adapter_rate" => rate(self.with_adaptor),
+            "short_rate" => {
+                rate(*self.discarded.get("too_short").unwrap_or(&0))
+            }
+            "discard_rate" => rate(discarded),
+            "kept_rate" => rate(self.reads_kept),
+            "bases_kept_rate" => {
+                self.bases_kept as f64 / max(self.bases, 1) as f64
+            }
+            "strand_invasion_rate" => rate(self.strand_invasion),
+            "flipped_rate" => rate(self.flipped),
+            "remnant_rate" => {
+                self.remnants as f64 / max(self.remnant_checked, 1) as f64
+            }
+            "q30_rate" => self.q30_bases as f64 / max(self.bases, 1) as f64,
+            "q30_rate_kept" => {
+                self.q30_bases_kept as f64 / max(self.bases_kept, 1) as f64
+            }
+            _ => return None,
+        })
+    }
+
+    /// Warnings about adaptor content that suggest a problem: no
+    /// adaptor at all may mean the wrong adaptor was given, and
+    /// adaptor at the start of almost every read means a library
+    /// of adaptor dimers. Too few reads give no warnings.
+    pub fn warnings(&self) -> Vec<String> {
+        const MIN_READS: usize = 1000;
+        let mut warnings = Vec::new();
+        if self.reads < MIN_READS {
+            return warnings;
+        }
+        let adaptor_rate = self.with_adaptor as f64 / self.reads as f64;
+        let dimer_rate = self.dimers as f64 / self.reads as f64;
+        if adaptor_rate < 0.001 {
+            warnings.push(format!(
+                "adaptor found in {:.4} of reads; is it the right adaptor?",
+                adaptor_rate
+            ));
+        }
+        if dimer_rate > 0.9 {
+            warnings.push(format!(
+                "adaptor at the start of {:.4} of reads; adaptor dimers?",
+                dimer_rate
+            ));
+        }
+        warnings
+    }
+}
+
+/// A condition on a metric, like "adapter_rate>0.5", that is checked
+/// against the stats at the end of a run.
+pub struct Condition {
+    pub text: String,
+    metric: String,
+    op: String,
+    value: f64,
+}
+
+impl Condition {
+    /// Parse a comma-separated list of conditions.
+    pub fn parse_list(text: &str) -> Result<Vec<Condition>, Box<dyn Error>> {
+        let mut conds = Vec::new();
+        for c in text.split(',').map(|x| x.trim()) {
+            let bad = || format!("bad condition: {}", c);
+            let i = c.find(|x| "<>=".contains(x)).ok_or_else(bad)?;
+            let j = c[i..].find(|x| !"<>=".contains(x)).ok_or_else(bad)? + i;
+            let (metric, op) = (&c[..i], &c[i..j]);
+            if Stats::default().metric(metric).is_none() {
+                return Err(format!("unknown metric: {}", metric))?;
+            }
+            if !["<", ">", "<=", ">="].contains(&op) {
+                return Err(bad())?;
+            }
+            conds.push(Condition {
+                text: c.to_string(),
+                metric: metric.to_string(),
+                op: op.to_string(),
+                value: c[j..].parse().map_err(|_| bad())?,
+            });
+        }
+        Ok(conds)
+    }
+
+    /// The value of the metric if the condition holds.
+    pub fn holds(&self, stats: &Stats) -> Option<f64> {
+        let x = stats.metric(&self.metric)?;
+        let holds = match self.op.as_str() {
+            "<" => x < self.value,
+            ">" => x > self.value,
+            "<=" => x <= self.value,
+            _ => x >= self.value,
+        };
+        if holds {
+            Some(x)
+        } else {
+            None
+        }
+    }
+}
+
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.skipped > 0 {
+            writeln!(f, "reads skipped: {}", self.skipped)?;
+        }
+        if self.stopped {
+            // reads skipped by this run are also done
+            let done = self.skipped + self.reads;
+            writeln!(f, "stopped early; resume with: --skip-reads {}", done)?;
+        }
+        writeln!(f, "reads: {}", self.reads)?;
+        writeln!(f, "reads kept: {}", self.reads_kept)?;
+        writeln!(f, "bases: {}", self.bases)?;
+        writeln!(f, "bases kept: {}", self.bases_kept)?;
+        let q30 = |x: usize, n: usize| x as f64 / max(n, 1) as f64;
+        writeln!(
+            f,
+            "bases Q30: {} ({:.4})",
+            self.q30_bases,
+            q30(self.q30_bases, self.bases)
+        )?;
+        writeln!(
+            f,
+            "bases kept Q30: {} ({:.4})",
+            self.q30_bases_kept,
+            q30(self.q30_bases_kept, self.bases_kept)
+        )?;
+        writeln!(
+            f,
+            "bases trimmed: adaptor {}, quality {}, N {}, other {}",
+            self.cut_adaptor, self.cut_quality, self.cut_n, self.cut_other
+        )?;
+        writeln!(f, "reads with adaptor: {}", self.with_adaptor)?;
+        writeln!(f, "reads with 5' adaptor: {}", self.with_front)?;
+        writeln!(f, "reads with linked adaptors: {}", self.with_linked)?;
+        writeln!(f, "reads with poly-X tail: {}", self.with_poly_x)?;
+        writeln!(f, "reads with primer read-through: {}", self.with_primer)?;
+        writeln!(f, "adaptor dimers: {}", self.dimers)?;
+        writeln!(f, "strand invasion artifacts: {}", self.strand_invasion)?;
+        writeln!(f, "reads reverse-complemented: {}", self.flipped)?;
+        writeln!(f, "reads split at hairpin: {}", self.hairpins)?;
+        if self.concatemers > 0 {
+            writeln!(f, "concatemers split: {}", self.concatemers)?;
+            writeln!(f, "concatemer subreads: {}", self.subreads)?;
+        }
+        if self.tagged > 0 {
+            writeln!(f, "reads with adaptor in comment: {}", self.tagged)?;
+        }
+        for (reason, count) in &self.discarded {
+            writeln!(f, "discarded {}: {}", reason, count)?;
+        }
+        if self.unpaired > 0 {
+            writeln!(f, "reads written unpaired: {}", self.unpaired)?;
+        }
+        if self.blank_lines > 0 {
+            writeln!(f, "blank lines skipped: {}", self.blank_lines)?;
+        }
+        if self.plus_mismatch > 0 {
+            writeln!(f, "\"+\" lines not the name: {}", self.plus_mismatch)?;
+        }
+        for (name, count) in &self.screen {
+            let frac = *count as f64 / max(self.screened, 1) as f64;
+            writeln!(f, "screen {}: {} ({:.4})", name, count, frac)?;
+        }
+        if self.remnant_checked > 0 {
+            let frac = self.remnants as f64 / self.remnant_checked as f64;
+            writeln!(f, "adaptor remnants: {} ({:.4})", self.remnants, frac)?;
+        }
+        for (bin, x) in &self.by_length {
+            let lo = bin * self.length_bin;
+            let hi = lo + self.length_bin - 1;
+            writeln!(
+                f,
+                "length {}-{}: reads {}, adaptor rate {:.4}, trimmed bases {:.4}",
+                lo,
+                hi,
+                x.reads,
+                x.with_adaptor as f64 / x.reads as f64,
+                x.bases_trimmed as f64 / max(x.bases, 1) as f64,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The adaptors of a common library kit, selected by name, with
+/// settings that suit it: the 3' adaptors, those for the second end
+/// of paired reads if different, the minimum read length, any tail to
+/// trim, and for single-cell kits the lengths of the cell barcode and
+/// UMI at the start of the first end.
+pub struct Preset {
+    pub name: &'static str,
+    pub adaptors: &'static [&'static str],
+    pub adaptors2: &'static [&'static str],
+    pub min_length: usize,
+    pub poly_x: Option<(u8, usize)>,
+    pub barcode: Option<(usize, usize)>,
+}
+
+pub const PRESETS: &[Preset] = &[
+    Preset {
+        name: "truseq",
+        adaptors: &["AGATCGGAAGAGC"],
+        adaptors2: &[],
+        min_length: 0,
+        poly_x: None,
+        barcode: None,
+    },
+    Preset {
+        name: "nextera",
+        adaptors: &["CTGTCTCTTATACACATCT"],
+        adaptors2: &[],
+        min_length: 0,
+        poly_x: None,
+        barcode: None,
+    },
+    // miRNAs are about 22 bases, so anything much shorter is noise
+    Preset {
+        name: "small-rna",
+        adaptors: &["TGGAATTCTCGGGTGCCAAGG"],
+        adaptors2: &[],
+        min_length: 18,
+        poly_x: None,
+        barcode: None,
+    },
+    Preset {
+        name: "bgi",
+        adaptors: &["AAGTCGGAGGCCAAGCGGTCTTAGGAAGACAA"],
+        adaptors2: &["AAGTCGGATCGTAGCCATGTCGTTCTGTGAGCCAAGGAGTTG"],
+        min_length: 0,
+        poly_x: None,
+        barcode: None,
+    },
+    // the first end is a 16 base cell barcode and a 12 base UMI, then
+    // the poly-T that primed the cDNA, so only the second end, which
+    // can run into the poly-A tail and then the adaptor, is trimmed
+    Preset {
+        name: "10x-3prime",
+        adaptors: &[],
+        adaptors2: &["AGATCGGAAGAGCGTCGTGTAG"],
+        min_length: 0,
+        poly_x: Some((b'A', 10)),
+        barcode: Some((16, 12)),
+    },
+    // both ends are from the genome, with the cell barcode in an index
+    // read of its own
+    Preset {
+        name: "10x-atac",
+        adaptors: &["CTGTCTCTTATACACATCT"],
+        adaptors2: &[],
+        min_length: 0,
+        poly_x: None,
+        barcode: None,
+    },
+];
+
+/// Params holds the settings that are shared by every input file in
+/// a run.
+pub struct Params {
+    pub zip: bool,                                 // compress output
+    pub no_eof_block: bool,                        // no empty block to end BGZF
+    pub gzip: bool,             // gzip, not BGZF, if compressed
+    pub zstd: bool,             // zstd, not BGZF, if compressed
+    pub zstd_dict: bool,        // train a dictionary for zstd
+    pub n_threads: u32,         // threads for htslib and rayon
+    pub buf_sz: usize,          // starting input buffer size
+    pub max_buf_sz: usize,      // largest input buffer size
+    pub pair_batch: usize,      // fewest pairs in a task
+    pub pair_inserts: bool,     // find insert sizes of pairs
+    pub adaptors: Vec<Vec<u8>>, // adaptor sequences
+    pub adaptors2: Option<Vec<Vec<u8>>>, // for second end if different
+    pub max_errors: usize,      // mismatches in adaptors
+    pub stringency: [(Stringency, Stringency); 2], // 3' and 5', each end
+    pub adaptor_tag: Option<Vec<u8>>, // comment field with adaptor
+    pub front: Vec<Vec<u8>>,    // 5' adaptors, ^ if anchored
+    pub linked: Vec<(Vec<u8>, Vec<u8>)>, // linked 5' and 3' adaptors
+    pub hp_edge: Option<HomopolymerEdge>, // homopolymer edge for adaptor
+    pub poly_x: Option<(u8, usize)>, // tail base and min run to trim
+    pub barcode: Option<(usize, usize)>, // end 1 is cell barcode and UMI
+    pub primers: Vec<Vec<u8>>,  // amplicon primers, 5' to 3'
+    pub qual_trimmer: Box<dyn QualityTrimmer>, // finds low quality ends
+    pub min_length: usize,      // discard shorter reads
+    pub max_n: Option<f64>,     // max Ns, or fraction if below 1
+    pub max_ee: Option<f64>,    // max expected errors
+    pub min_complexity: f64,    // discard simpler reads
+    pub prefix: Vec<u8>,        // fixed 5' sequence to remove
+    pub prefix_mm: usize,       // mismatches allowed in prefix
+    pub invasion: Option<Invasion>, // strand invasion artifacts
+    pub subsample: Option<usize>, // number of reads to keep
+    pub seed: u64,              // for all random choices
+    pub recal: Option<[u8; 256]>, // output quality score mapping
+    pub orient: Vec<u8>,        // read orientation signature
+    pub passthrough: bool,      // write input unchanged
+    pub length_bin: usize,      // width of length bins in stats
+    pub screen: Option<Screen>, // references for composition
+    pub split_by_lane: bool,    // one output file for each lane
+    pub trim_n: bool,           // remove Ns at ends of reads
+    pub n_window: Option<(usize, usize)>, // end reads at k Ns in w bases
+    pub hairpin: Vec<u8>,       // hairpin in duplex reads
+    pub concatemer: Vec<u8>,    // backbone between copies
+    pub ignore_first: usize,    // adaptor never starts before
+    pub mask_quality: bool,     // low quality ends to N
+    pub emit_coords: bool,      // trimmed coordinates in names
+    pub explain: Option<Vec<u8>>, // read to explain trimming for
+    pub remnant_reads: usize,   // reads to check for remnants
+    pub phred64: bool,          // quality scores are Phred+64
+    pub permissive: bool,       // allow blank lines and "+" text
+    pub skip_reads: usize,      // reads done in an earlier run
+    pub deadline: Option<Instant>, // stop after this time
+    pub tui: bool,              // live dashboard on stderr
+    pub after_first_batch: Option<fn(usize)>, // given the reads so far
+    // more stats, for code that uses this library
+    pub collectors: Vec<Arc<dyn StatsCollector>>,
+}
+
+impl Default for Params {
+    /// The same settings as the command line defaults.
+    fn default() -> Params {
+        Params {
+            zip: false,
+            no_eof_block: false,
+            gzip: false,
+            zstd: false,
+            zstd_dict: false,
+            n_threads: 1,
+            buf_sz: 64 * 1024,
+            max_buf_sz: 64 * 1024 * 1024,
+            pair_batch: 1,
+            pair_inserts: false,
+            adaptors: vec![b"AGATCGGAAGAGC".to_vec()],
+            adaptors2: None,
+            max_errors: 0,
+            stringency: Default::default(),
+            adaptor_tag: None,
+            front: Vec::new(),
+            linked: Vec::new(),
+            hp_edge: None,
+            poly_x: None,
+            barcode: None,
+            primers: Vec::new(),
+            qual_trimmer: Box::new(Cutadapt { front: 0, back: 20 }),
+            min_length: 0,
+            max_n: None,
+            max_ee: None,
+            min_complexity: 0.0,
+            prefix: Vec::new(),
+            prefix_mm: 2,
+            invasion: None,
+            subsample: None,
+            seed: 0,
+            recal: None,
+            orient: Vec::new(),
+            passthrough: false,
+            length_bin: 0,
+            screen: None,
+            split_by_lane: false,
+            trim_n: true,
+            n_window: None,
+            hairpin: Vec::new(),
+            concatemer: Vec::new(),
+            ignore_first: 0,
+            mask_quality: false,
+            emit_coords: false,
+            explain: None,
+            remnant_reads: 0,
+            phred64: false,
+            permissive: false,
+            skip_reads: 0,
+            deadline: None,
+            tui: false,
+            after_first_batch: None,
+            collectors: Vec::new(),
+        }
+    }
+}
+
+/// Check an adaptor sequence for likely mistakes. Characters other
+/// than upper case IUPAC nucleotide codes are an error, as is an
+/// adaptor of only N. Things that are allowed but suspicious are
+/// returned as warnings.
+pub fn check_adaptor(adaptor: &[u8]) -> Result<Vec<String>, Box<dyn Error>> {
+    const MIN_LEN: usize = 6;
+    const MAX_LEN: usize = 100;
+    if let Some(&x) = adaptor.iter().find(|x| !b"ACGTNRYSWKMBDHV".contains(x)) {
+        let what = match x {
+            b'a'..=b'z' => "lower case",
+            _ if x.is_ascii_whitespace() => "whitespace",
+            _ => "invalid character",
+        };
+        Err(format!("{} in adaptor: {:?}", what, x as char))?;
+    }
+    if adaptor.iter().all(|&x| x == b'N') {
+        Err("adaptor is all N")?;
+    }
+    let mut warnings = Vec::new();
+    if adaptor.len() < MIN_LEN {
+        warnings.push(format!("adaptor is short: {} bases", adaptor.len()));
+    }
+    if adaptor.len() > MAX_LEN {
+        warnings.push(format!("adaptor is long: {} bases", adaptor.len()));
+    }
+    let rc: Vec<u8> = adaptor.iter().rev().map(|&x| complement(x)).collect();
+    if rc == adaptor {
+        warnings.push("adaptor is its own reverse complement".to_string());
+    }
+    for (base, name) in [(b'A', "poly-A"), (b'G', "poly-G")] {
+        if adaptor.iter().all(|&x| x == base) {
+            warnings.push(format!("adaptor is a {} artifact", name));
+        }
+    }
+    Ok(warnings)
+}
+
+/// Read a quality recalibration table: each line has an observed
+/// and an adjusted quality score as Phred values. Scores not in the
+/// table are left as they are. The returned table maps characters in
+/// the quality string, so it can be applied directly to the output.
+pub fn read_recal_table(filename: &str) -> Result<[u8; 256], Box<dyn Error>> {
+    const QUAL_BASE: u8 = 33; // assumes base quality starts at 33
+    let mut table = [0u8; 256];
+    table.iter_mut().enumerate().for_each(|(i, x)| *x = i as u8);
+    let text = std::fs::read_to_string(filename)?;
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let bad_line = || format!("bad line in {}: {}", filename, line);
+        let vals = line
+            .split_whitespace()
+            .map(|x| x.parse::<u8>())
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| bad_line())?;
+        match vals[..] {
+            [from, to] if from <= 93 && to <= 93 => {
+                table[(from + QUAL_BASE) as usize] = to + QUAL_BASE
+            }
+            _ => return Err(bad_line())?,
+        }
+    }
+    Ok(table)
+}
+
+/// Read amplicon primers from a FASTA file, upper case and 5' to 3'.
+pub fn read_primers(filename: &str) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+    let text = std::fs::read(filename)?;
+    let primers: Vec<Vec<u8>> = text
+        .split(|&x| x == b'>')
+        .filter(|x| !x.is_empty())
+        .map(|rec| {
+            rec.split(|&x| x == b'\n')
+                .skip(1)
+                .flatten()
+                .filter(|x| !x.is_ascii_whitespace())
+                .map(|x| x.to_ascii_uppercase())
+                .collect::<Vec<u8>>()
+        })
+        .filter(|x| !x.is_empty())
+        .collect();
+    if primers.is_empty() {
+        Err(format!("no primers in {}", filename))?;
+    }
+    Ok(primers)
+}
+
+/// The empty block that marks the end of a BGZF file.
+const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00,
+    0x42, 0x43, 0x02, 0x00, 0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00,
+];
+
+/// Add the contents of the file `from` to the end of the file `to`,
+/// then remove `from`. If `to` is BGZF, its EOF block is removed first
+/// so there is only one, at the end. Both BGZF and plain text can be
+/// joined this way.
+pub fn append_output(from: &str, to: &str) -> Result<(), Box<dyn Error>> {
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom};
+    if !std::path::Path::new(to).exists() {
+        std::fs::rename(from, to)?;
+        return Ok(());
+    }
+    let mut out = OpenOptions::new().read(true).write(true).open(to)?;
+    let len = out.metadata()?.len();
+    let n = BGZF_EOF.len() as u64;
+    if len >= n {
+        let mut tail = [0u8; 28];
+        out.seek(SeekFrom::Start(len - n))?;
+        out.read_exact(&mut tail)?;
+        if tail == BGZF_EOF {
+            out.set_len(len - n)?;
+        }
+    }
+    out.seek(SeekFrom::End(0))?;
+    std::io::copy(&mut std::fs::File::open(from)?, &mut out)?;
+    std::fs::remove_file(from)?;
+    Ok(())
+}
+
+/// The first bytes of a zstd frame.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// The first bytes of a zstd skippable frame, which decoders pass over.
+const ZSTD_SKIPPABLE_MAGIC: [u8; 4] = [0x50, 0x2a, 0x4d, 0x18];
+
+/// The first bytes of compressed formats that can't be read, so
+/// these files are not taken for plain text.
+const UNSUPPORTED_MAGIC: [(&str, &[u8]); 4] = [
+    ("bzip2", b"BZh"),
+    ("xz", &[0xfd, b'7', b'z', b'X', b'Z', 0x00]),
+    ("zip", &[b'P', b'K', 0x03, 0x04]),
+    ("lz4", &[0x04, 0x22, 0x4d, 0x18]),
+];
+
+/// An input file: zstd, or anything htslib reads, which is BGZF,
+/// gzip or plain text.
+enum Input {
+    Bgzf(bgzf::Reader),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Decoder<'static, std::io::BufReader<std::fs::File>>),
+    // the start, already read to look at, then the rest
+    Peeked(std::io::Chain<std::io::Cursor<Vec<u8>>, Box<Input>>),
+}
+
+impl Input {
+    /// Threads only help with BGZF, as for Output.
+    fn set_thread_pool(
+        &mut self,
+        tpool: &ThreadPool,
+    ) -> Result<(), Box<dyn Error>> {
+        match self {
+            Input::Bgzf(r) => r.set_thread_pool(tpool)?,
+            #[cfg(feature = "zstd")]
+            Input::Zstd(_) => (),
+            // too late once reading has started
+            Input::Peeked(_) => (),
+        }
+        Ok(())
+    }
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Input::Bgzf(r) => r.read(buf),
+            #[cfg(feature = "zstd")]
+            Input::Zstd(r) => r.read(buf),
+            Input::Peeked(r) => r.read(buf),
+        }
+    }
+}
+
+/// Read the start of an input to see if it has interleaved pairs: if
+/// the first two records have the same name, as the two ends of a
+/// pair would. The input is given back with what was read put back.
+fn peek_interleaved(
+    mut reader: Input,
+) -> Result<(Input, bool), Box<dyn Error>> {
+    let mut head = Vec::new();
+    let mut chunk = [0u8; 4096];
+    while head.iter().filter(|&&x| x == b'\n').count() < 8 {
+        let n = reader.read(&mut chunk)?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&chunk[..n]);
+    }
+    let mut lines = head.split(|&x| x == b'\n');
+    let name = |x: Option<&[u8]>| {
+        let x = x?.strip_prefix(b"@")?;
+        Some(pair_name(x.strip_suffix(b"\r").unwrap_or(x)).to_vec())
+    };
+    let first = name(lines.next());
+    let pairs = first.is_some() && first == name(lines.nth(3));
+    let head = std::io::Cursor::new(head);
+    Ok((Input::Peeked(head.chain(Box::new(reader))), pairs))
+}
+
+/// The dictionary written first in a zstd file by --zstd-dict, or
+/// nothing if there is none, which the decoder takes as no dictionary.
+#[cfg(feature = "zstd")]
+fn read_zstd_dict(filename: &str) -> std::io::Result<Vec<u8>> {
+    let mut file = std::fs::File::open(filename)?;
+    let mut header = Vec::new();
+    (&mut file).take(8).read_to_end(&mut header)?;
+    let mut dict = Vec::new();
+    if header.len() == 8 && header.starts_with(&ZSTD_SKIPPABLE_MAGIC) {
+        let sz = u32::from_le_bytes(header[4..].try_into().unwrap());
+        file.take(sz as u64).read_to_end(&mut dict)?;
+    }
+    if !dict.starts_with(&ZSTD_DICT_MAGIC) {
+        dict.clear();
+    }
+    Ok(dict)
+}
+
+/// Open an input file, where "-" is standard input. For outputs,
+/// htslib itself takes "-" to be standard output. The reader is
+/// picked from the first bytes of the file, whatever its name, so
+/// standard input can't be zstd.
+fn open_input(filename: &str) -> Result<Input, Box<dyn Error>> {
+    if filename == "-" {
+        return Ok(Input::Bgzf(bgzf::Reader::from_stdin()?));
+    }
+    let mut magic = Vec::new();
+    std::fs::File::open(filename)?
+        .take(8)
+        .read_to_end(&mut magic)?;
+    let is_zstd = magic.starts_with(&ZSTD_MAGIC)
+        || magic.starts_with(&ZSTD_SKIPPABLE_MAGIC);
+    #[cfg(feature = "zstd")]
+    if is_zstd {
+        let dict = read_zstd_dict(filename)?;
+        let file = std::io::BufReader::new(std::fs::File::open(filename)?);
+        return Ok(Input::Zstd(zstd::Decoder::with_dictionary(file, &dict)?));
+    }
+    #[cfg(not(feature = "zstd"))]
+    if is_zstd {
+        Err(format!(
+            "zstd input needs a build with --features zstd: {}",
+            filename
+        ))?;
+    }
+    if let Some((format, _)) =
+        UNSUPPORTED_MAGIC.iter().find(|(_, x)| magic.starts_with(x))
+    {
+        Err(format!(
+            "input in {} format can't be read, only plain text, gzip, \
+             BGZF or zstd: {}",
+            format, filename
+        ))?;
+    }
+    // htslib reads gzip and BGZF, and anything else as plain text
+    Ok(Input::Bgzf(bgzf::Reader::from_path(filename)?))
+}
+
+/// Check the first `n_reads` reads of an input, after any skipped
+/// with `--skip-reads`, for signs that it was already trimmed: the
+/// adaptors are almost never found and quality trimming would almost
+/// never remove bases. Too few reads to tell give false.
+pub fn already_trimmed(
+    params: &Params,
+    adaptors: &[Vec<u8>],
+    input: &str,
+    n_reads: usize,
+) -> Result<bool, Box<dyn Error>> {
+    const MIN_READS: usize = 1000;
+    const MAX_RATE: f64 = 0.001;
+    const MIN_OVERLAP: usize = 10;
+    let adaptors = Adaptors::new(adaptors, params, 0);
+    let mut reader = open_input(input)?;
+    let mut input = FQInput::new(&mut reader, params);
+    let (mut reads, mut with_adaptor, mut qual_trimmed) = (0, 0, 0);
+    let mut to_skip = params.skip_reads;
+    let mut qual = Vec::new(); // reused for each read
+    while reads < n_reads {
+        input.fill()?;
+        // reads done in an earlier run
+        let n_skip = min(to_skip, input.recs.len());
+        input.recs.drain(..n_skip);
+        to_skip -= n_skip;
+        input.truncate(n_reads - reads);
+        let buf = &input.buf;
+        for x in &input.recs {
+            // short matches at the end of a read are often by chance
+            let read = &buf[x.r..x.r + x.stop];
+            let found = adaptors.back.iter().any(|a| {
+                let overlap = min(MIN_OVERLAP, a.seq.len());
+                a.find(read, x.stop) + overlap <= x.stop
+            });
+            with_adaptor += found as usize;
+            // quality scores as they would be for trimming
+            qual.clear();
+            qual.extend(buf[x.q..x.q + x.stop].iter().map(|&q| {
+                if params.phred64 {
+                    q - 31
+                } else {
+                    q
+                }
+            }));
+            let (qstart, qstop) = params.qual_trimmer.trim(&qual);
+            qual_trimmed += (qstart > 0 || qstop < x.stop) as usize;
+        }
+        reads += input.recs.len();
+        if input.eof {
+            break;
+        }
+    }
+    let rate = |x: usize| x as f64 / max(reads, 1) as f64;
+    Ok(reads >= min(MIN_READS, n_reads)
+        && rate(with_adaptor) < MAX_RATE
+        && rate(qual_trimmed) < MAX_RATE)
+}
+
+/// The number of records in a FASTQ file, compressed or not, for
+/// checking the output.
+pub fn count_records(filename: &str) -> Result<usize, Box<dyn Error>> {
+    let mut reader = open_input(filename)?;
+    let mut buf = vec![0u8; 256 * 1024];
+    let mut lines = 0;
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        lines += buf[..n].iter().filter(|&&x| x == b'\n').count();
+    }
+    Ok(lines / 4)
+}
+
+pub fn remove_adaptors(
+    params: &Params,
+    input: &str,
+    output: &str,
+    discarded: Option<&str>,
+) -> Result<Stats, Box<dyn Error>> {
+    remove_adaptors_from(params, open_input(input)?, input, output, discarded)
+}
+
+/// Like remove_adaptors, but with the input already opened. It is
+/// opened again by name for a second pass to write a subsample.
+fn remove_adaptors_from(
+    params: &Params,
+    mut reader: Input,
+    input: &str,
+    output: &str,
+    discarded: Option<&str>,
+) -> Result<Stats, Box<dyn Error>> {
+    // without compression, plain text rather than BGZF blocks that
+    // are not compressed, which look like gzip to other tools
+    let lvl = match params.zip {
+        true => CompLvl::Default,
+        false => CompLvl::Uncompressed,
+    };
+    let codec = Codec::new(params);
+    let mut writer = Output::new(output, lvl, codec)?;
+    let mut discarded = match discarded {
+        Some(x) => Some(Output::new(x, lvl, codec)?),
+        None => None,
     };
-    let mut reader = bgzf::Reader::from_path(input)?;
-    let mut writer = bgzf::Writer::from_path_with_level(output, lvl)?;
 
-    let tpool = ThreadPool::new(n_threads - 1)?;
-    if n_threads > 1 {
+    let tpool = ThreadPool::new(params.n_threads - 1)?;
+    if params.n_threads > 1 {
         reader.set_thread_pool(&tpool)?;
         writer.set_thread_pool(&tpool)?;
+        if let Some(d) = discarded.as_mut() {
+            d.set_thread_pool(&tpool)?;
+        }
+    }
+    let mut subsample =
+        params.subsample.map(|x| Subsample::new(x, params.seed));
+    let mut lanes = match params.split_by_lane {
+        true => Some(Lanes {
+            output,
+            lvl,
+            codec,
+            tpool: if params.n_threads > 1 {
+                Some(&tpool)
+            } else {
+                None
+            },
+            writers: HashMap::new(),
+        }),
+        false => None,
+    };
+    let mut monitor = params
+        .tui
+        .then(|| Monitor::new(input_size(input)))
+        .flatten();
+    let mut stats = process_reads(
+        params,
+        &mut reader,
+        &mut writer,
+        discarded.as_mut(),
+        subsample.as_mut(),
+        lanes.as_mut(),
+        monitor.as_mut(),
+    )?;
+
+    // go back for the reads chosen
+    if let Some(s) = subsample.as_mut() {
+        if !stats.stopped {
+            s.start_second_pass();
+            let mut reader = open_input(input)?;
+            if params.n_threads > 1 {
+                reader.set_thread_pool(&tpool)?;
+            }
+            let more = process_reads(
+                params,
+                &mut reader,
+                &mut writer,
+                None,
+                Some(s),
+                lanes.as_mut(),
+                None,
+            )?;
+            stats.written += more.written;
+        }
+    }
+    // errors writing the last of the output would be lost on drop
+    writer.finish()?;
+    if let Some(d) = discarded {
+        d.finish()?;
+    }
+    if let Some(l) = lanes {
+        l.finish()?;
+    }
+    Ok(stats)
+}
+
+/// Like remove_adaptors, but for the two ends of paired reads, which
+/// are processed together so the outputs stay in sync. With the same
+/// output for both ends, the pairs are written interleaved, with the
+/// two ends of each pair one after the other.
+pub fn remove_adaptors_paired(
+    params: &Params,
+    inputs: (&str, &str),
+    outputs: (&str, &str),
+    discarded: Option<&str>,
+    unpaired: (Option<&str>, Option<&str>),
+) -> Result<(Stats, Stats, PairStats), Box<dyn Error>> {
+    // a read split in two would put the ends out of sync
+    if !params.hairpin.is_empty() || !params.concatemer.is_empty() {
+        Err("read splitting not supported for paired end")?;
+    }
+    let interleaved = outputs.0 == outputs.1;
+    if interleaved && params.split_by_lane {
+        Err("split by lane needs an output for each end")?;
+    }
+    // without compression, plain text rather than BGZF blocks that
+    // are not compressed, which look like gzip to other tools
+    let lvl = match params.zip {
+        true => CompLvl::Default,
+        false => CompLvl::Uncompressed,
+    };
+    let codec = Codec::new(params);
+    let new_output = |x: Option<&str>| match x {
+        Some(x) => Output::new(x, lvl, codec).map(|x| Some(RefCell::new(x))),
+        None => Ok(None),
+    };
+    let mut reader1 = open_input(inputs.0)?;
+    let mut reader2 = open_input(inputs.1)?;
+    let writer1 = RefCell::new(Output::new(outputs.0, lvl, codec)?);
+    let writer2 = new_output((!interleaved).then_some(outputs.1))?;
+    let discarded = new_output(discarded)?;
+    let unpaired1 = new_output(unpaired.0)?;
+    let unpaired2 = new_output(unpaired.1)?;
+
+    let tpool = ThreadPool::new(params.n_threads - 1)?;
+    if params.n_threads > 1 {
+        reader1.set_thread_pool(&tpool)?;
+        reader2.set_thread_pool(&tpool)?;
+        writer1.borrow_mut().set_thread_pool(&tpool)?;
+        let others = [&writer2, &discarded, &unpaired1, &unpaired2];
+        for d in others.into_iter().flatten() {
+            d.borrow_mut().set_thread_pool(&tpool)?;
+        }
+    }
+    let mut subsample =
+        params.subsample.map(|x| Subsample::new(x, params.seed));
+    let new_lanes = |output| Lanes {
+        output,
+        lvl,
+        codec,
+        tpool: if params.n_threads > 1 {
+            Some(&tpool)
+        } else {
+            None
+        },
+        writers: HashMap::new(),
+    };
+    let mut lanes = match params.split_by_lane {
+        true => Some((new_lanes(outputs.0), new_lanes(outputs.1))),
+        false => None,
+    };
+    let mut out1 = SharedWriter(&writer1);
+    let mut out2 = SharedWriter(writer2.as_ref().unwrap_or(&writer1));
+    let mut discarded_out = discarded.as_ref().map(SharedWriter);
+    let mut unpaired_out = (
+        unpaired1.as_ref().map(SharedWriter),
+        unpaired2.as_ref().map(SharedWriter),
+    );
+    // the time left is known only if both sizes are
+    let total = input_size(inputs.0).zip(input_size(inputs.1));
+    let total = total.map(|(a, b)| a + b);
+    let mut monitor = params.tui.then(|| Monitor::new(total)).flatten();
+    let mut stats = process_pairs(
+        params,
+        (&mut reader1, &mut reader2),
+        PairOutputs {
+            ends: (&mut out1, &mut out2),
+            discarded: discarded_out.as_mut(),
+            unpaired: (unpaired_out.0.as_mut(), unpaired_out.1.as_mut()),
+        },
+        subsample.as_mut(),
+        lanes.as_mut().map(|(a, b)| (a, b)),
+        monitor.as_mut(),
+    )?;
+
+    // go back for the pairs chosen
+    if let Some(s) = subsample.as_mut() {
+        if !stats.0.stopped {
+            s.start_second_pass();
+            let mut reader1 = open_input(inputs.0)?;
+            let mut reader2 = open_input(inputs.1)?;
+            if params.n_threads > 1 {
+                reader1.set_thread_pool(&tpool)?;
+                reader2.set_thread_pool(&tpool)?;
+            }
+            let more = process_pairs(
+                params,
+                (&mut reader1, &mut reader2),
+                PairOutputs {
+                    ends: (&mut out1, &mut out2),
+                    discarded: None,
+                    unpaired: (None, None),
+                },
+                Some(s),
+                lanes.as_mut().map(|(a, b)| (a, b)),
+                None,
+            )?;
+            stats.0.written += more.0.written;
+            stats.1.written += more.1.written;
+        }
+    }
+    // errors writing the last of the output would be lost on drop
+    let others = [writer2, discarded, unpaired1, unpaired2];
+    for w in [Some(writer1)].into_iter().chain(others).flatten() {
+        w.into_inner().finish()?;
+    }
+    if let Some((l1, l2)) = lanes {
+        l1.finish()?;
+        l2.finish()?;
+    }
+    Ok(stats)
+}
+
+/// Like remove_adaptors, but the input may have interleaved pairs,
+/// with the two ends of each pair one after the other, as from
+/// `samtools fastq`. If `interleaved` is None, the reads are pairs if
+/// the first two have the same name. Pairs are written interleaved.
+/// There are stats for each end, so only one for single reads, and
+/// stats for pairs only if there are pairs.
+pub fn remove_adaptors_interleaved(
+    params: &Params,
+    input: &str,
+    output: &str,
+    discarded: Option<&str>,
+    unpaired: (Option<&str>, Option<&str>),
+    interleaved: Option<bool>,
+) -> Result<(Vec<Stats>, Option<PairStats>), Box<dyn Error>> {
+    let reader = open_input(input)?;
+    let (mut reader, pairs) = match interleaved {
+        Some(x) => (reader, x),
+        None => peek_interleaved(reader)?,
+    };
+    if !pairs {
+        let stats =
+            remove_adaptors_from(params, reader, input, output, discarded)?;
+        return Ok((vec![stats], None));
+    }
+    // a read split in two would put the ends out of sync, and both
+    // ends go to one output, so they can't be split by lane
+    if !params.hairpin.is_empty() || !params.concatemer.is_empty() {
+        Err("read splitting not supported for paired end")?;
+    }
+    if params.subsample.is_some() || params.split_by_lane {
+        Err("subsample and split by lane not supported for interleaved")?;
+    }
+    let lvl = match params.zip {
+        true => CompLvl::Default,
+        false => CompLvl::Uncompressed,
+    };
+    let codec = Codec::new(params);
+    let new_output = |x: Option<&str>| match x {
+        Some(x) => Output::new(x, lvl, codec).map(|x| Some(RefCell::new(x))),
+        None => Ok(None),
+    };
+    let writer = RefCell::new(Output::new(output, lvl, codec)?);
+    let discarded = new_output(discarded)?;
+    let unpaired1 = new_output(unpaired.0)?;
+    let unpaired2 = new_output(unpaired.1)?;
+
+    let tpool = ThreadPool::new(params.n_threads - 1)?;
+    if params.n_threads > 1 {
+        reader.set_thread_pool(&tpool)?;
+        writer.borrow_mut().set_thread_pool(&tpool)?;
+        let others = [&discarded, &unpaired1, &unpaired2];
+        for d in others.into_iter().flatten() {
+            d.borrow_mut().set_thread_pool(&tpool)?;
+        }
+    }
+    let pairs = RefCell::new(Deinterleave::new(BufReader::new(reader)));
+    let mut end1 = InterleavedEnd {
+        pairs: &pairs,
+        end: 0,
+    };
+    let mut end2 = InterleavedEnd {
+        pairs: &pairs,
+        end: 1,
+    };
+    let (mut out1, mut out2) = (SharedWriter(&writer), SharedWriter(&writer));
+    let mut discarded_out = discarded.as_ref().map(SharedWriter);
+    let mut unpaired_out = (
+        unpaired1.as_ref().map(SharedWriter),
+        unpaired2.as_ref().map(SharedWriter),
+    );
+    let mut monitor = params
+        .tui
+        .then(|| Monitor::new(input_size(input)))
+        .flatten();
+    let (stats1, stats2, pair_stats) = process_pairs(
+        params,
+        (&mut end1, &mut end2),
+        PairOutputs {
+            ends: (&mut out1, &mut out2),
+            discarded: discarded_out.as_mut(),
+            unpaired: (unpaired_out.0.as_mut(), unpaired_out.1.as_mut()),
+        },
+        None,
+        None,
+        monitor.as_mut(),
+    )?;
+    // errors writing the last of the output would be lost on drop
+    writer.into_inner().finish()?;
+    for d in [discarded, unpaired1, unpaired2].into_iter().flatten() {
+        d.into_inner().finish()?;
+    }
+    Ok((vec![stats1, stats2], Some(pair_stats)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Trim reads given as FASTQ text, for the output and the stats.
+    fn trim(params: &Params, input: &[u8]) -> (Vec<u8>, Stats) {
+        let mut out = Vec::new();
+        let stats = process_reads(
+            params,
+            &mut &input[..],
+            &mut out,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        (out, stats)
+    }
+
+    /// Trim pairs given as FASTQ text for each end, for the output of
+    /// each end and of discarded pairs.
+    fn trim_pairs(
+        params: &Params,
+        input: (&str, &str),
+    ) -> Result<[String; 3], Box<dyn Error>> {
+        let (mut out1, mut out2, mut discarded) =
+            (Vec::new(), Vec::new(), Vec::new());
+        let outputs = PairOutputs {
+            ends: (&mut out1, &mut out2),
+            discarded: Some(&mut discarded),
+            unpaired: (None, None),
+        };
+        let readers = (&mut input.0.as_bytes(), &mut input.1.as_bytes());
+        process_pairs(params, readers, outputs, None, None, None)?;
+        Ok([out1, out2, discarded].map(|x| String::from_utf8(x).unwrap()))
+    }
+
+    fn record(name: &str, seq: &str) -> String {
+        format!("@{}\n{}\n+\n{}\n", name, seq, "I".repeat(seq.len()))
+    }
+
+    #[test]
+    fn adaptor_is_trimmed() {
+        let insert = "ACGTTGCAACGTTGCAACGT";
+        let input = record("r1", &format!("{}AGATCGGAAGAGCACAC", insert));
+        let (out, stats) = trim(&Params::default(), input.as_bytes());
+        assert_eq!(String::from_utf8(out).unwrap(), record("r1", insert));
+        assert_eq!(stats.reads, 1);
+        assert_eq!(stats.with_adaptor, 1);
+        assert_eq!(stats.reads_kept, 1);
+        assert_eq!(stats.bases_kept, insert.len());
+    }
+
+    #[test]
+    fn adaptor_at_end_is_trimmed() {
+        let insert = "ACGTTGCAACGTTGCAACGT";
+        let input = record("r1", &format!("{}AGATCG", insert));
+        let (out, stats) = trim(&Params::default(), input.as_bytes());
+        assert_eq!(String::from_utf8(out).unwrap(), record("r1", insert));
+        assert_eq!(stats.with_adaptor, 1);
+    }
+
+    #[test]
+    fn short_read_is_discarded() {
+        let params = Params {
+            min_length: 25,
+            ..Default::default()
+        };
+        let input = record("r1", "ACGTTGCAACGTTGCAACGTAGATCGGAAGAGC");
+        let (out, stats) = trim(&params, input.as_bytes());
+        assert!(out.is_empty());
+        assert_eq!(stats.reads_kept, 0);
+        assert_eq!(stats.discarded.get("too_short"), Some(&1));
+
+        let (mut out, mut discarded) = (Vec::new(), Vec::new());
+        process_reads(
+            &params,
+            &mut input.as_bytes(),
+            &mut out,
+            Some(&mut discarded),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let discarded = String::from_utf8(discarded).unwrap();
+        assert!(discarded.starts_with("@r1 reason=too_short\n"));
+    }
+
+    #[test]
+    fn empty_input_gives_empty_output() {
+        let (out, stats) = trim(&Params::default(), b"");
+        assert!(out.is_empty());
+        assert_eq!(stats.reads, 0);
+        assert_eq!(stats.reads_kept, 0);
+        assert!(stats.discarded.is_empty());
+    }
+
+    #[test]
+    fn buffer_grows_for_long_names() {
+        let name = "x".repeat(1000);
+        let input = record(&name, "ACGTTGCAACGTTGCAACGTAGATCGGAAGAGC");
+        let params = Params {
+            buf_sz: 64,
+            ..Default::default()
+        };
+        let (out, stats) = trim(&params, input.as_bytes());
+        let (expected, _) = trim(&Params::default(), input.as_bytes());
+        assert_eq!(out, expected);
+        assert_eq!(stats.reads_kept, 1);
+    }
+
+    #[test]
+    fn phred64_quality_is_converted() {
+        // "h" is 40 and "B" is 2 in Phred+64, so the ends are trimmed
+        // and the rest written as Phred+33
+        let params = Params {
+            phred64: true,
+            ..Default::default()
+        };
+        let seq = "ACGTTGCAACGTTGCTACGT";
+        let input = ["r1", "r2"].map(|x| {
+            format!("@{}\n{}\n+\n{}{}\n", x, seq, "h".repeat(16), "BBBB")
+        });
+        let (out, stats) = trim(&params, input.concat().as_bytes());
+        let expected = ["r1", "r2"].map(|x| record(x, &seq[..16]));
+        assert_eq!(String::from_utf8(out).unwrap(), expected.concat());
+        assert_eq!(stats.reads_kept, 2);
+    }
+
+    #[test]
+    fn mates_stay_in_sync() {
+        // the second pair has a short insert in its first end only
+        let params = Params {
+            min_length: 15,
+            ..Default::default()
+        };
+        let insert = "ACGTTGCAACGTTGCAACGT";
+        let short = "ACGTTGCAAGATCGGAAGAGC";
+        let end = |x: &str, seq2: &str| {
+            let name = |n: &str| format!("{}/{}", n, x);
+            [
+                record(&name("p1"), insert),
+                record(&name("p2"), seq2),
+                record(&name("p3"), insert),
+            ]
+            .concat()
+        };
+        let input = (end("1", short), end("2", insert));
+        let [out1, out2, _] =
+            trim_pairs(&params, (&input.0, &input.1)).unwrap();
+        assert_eq!(out1, record("p1/1", insert) + &record("p3/1", insert));
+        assert_eq!(out2, record("p1/2", insert) + &record("p3/2", insert));
+    }
+
+    #[test]
+    fn mate_of_discarded_read_is_discarded() {
+        let params = Params {
+            min_length: 15,
+            ..Default::default()
+        };
+        let end1 = record("p1/1", "ACGTTGCAAGATCGGAAGAGC");
+        let end2 = record("p1/2", "ACGTTGCAACGTTGCAACGT");
+        let [out1, out2, discarded] =
+            trim_pairs(&params, (&end1, &end2)).unwrap();
+        assert!(out1.is_empty() && out2.is_empty());
+        assert!(discarded.contains("@p1/1 reason=too_short\n"));
+        assert!(discarded.contains("@p1/2 reason=mate_discarded\n"));
+    }
+
+    #[test]
+    fn mismatched_names_are_rejected() {
+        let end1 = record("p1/1", "ACGTTGCAACGTTGCAACGT");
+        let end2 = record("p2/2", "ACGTTGCAACGTTGCAACGT");
+        let err = trim_pairs(&Params::default(), (&end1, &end2)).unwrap_err();
+        assert!(err.to_string().contains("different names"));
+    }
+
+    #[test]
+    fn empty_pairs_give_empty_output() {
+        let outputs = trim_pairs(&Params::default(), ("", "")).unwrap();
+        assert!(outputs.iter().all(|x| x.is_empty()));
+    }
+
+    #[test]
+    fn records_across_buffer_boundaries() {
+        // the same as with a buffer that holds all the input at once;
+        // the largest has enough records in a batch to use threads
+        for buf_sz in [16, 31, 64, 100, 257, 1 << 16] {
+            let input = fixtures::buffer_boundary(buf_sz);
+            let params = Params {
+                buf_sz,
+                ..Default::default()
+            };
+            let whole = Params {
+                buf_sz: 2 * input.len(),
+                ..Default::default()
+            };
+            let (out, stats) = trim(&params, &input);
+            let (expected, expected_stats) = trim(&whole, &input);
+            assert_eq!(out, expected, "buffer size {}", buf_sz);
+            assert_eq!(stats.reads, expected_stats.reads);
+            assert_eq!(stats.with_adaptor, expected_stats.with_adaptor);
+        }
+    }
+
+    #[test]
+    fn zero_length_reads_are_kept() {
+        let (out, stats) = trim(&Params::default(), &fixtures::zero_length());
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(stats.reads, 6);
+        assert_eq!(stats.reads_kept, 6);
+        assert!(out.starts_with("@empty0\n\n+\n\n@insert\n"));
+        assert!(out.ends_with("@empty3\n\n+\n\n"));
+    }
+
+    #[test]
+    fn all_adaptor_reads_are_emptied() {
+        let input = fixtures::all_adaptor();
+        let (_, stats) = trim(&Params::default(), &input);
+        assert_eq!(stats.reads, 14);
+        assert_eq!(stats.bases_kept, 0);
+        let params = Params {
+            min_length: 1,
+            ..Default::default()
+        };
+        let (out, stats) = trim(&params, &input);
+        assert!(out.is_empty());
+        assert_eq!(stats.discarded.get("too_short"), Some(&14));
+    }
+
+    #[test]
+    fn subsample_prefers_unique_reads() {
+        // seven sequences, one of them in four reads
+        let seqs = ["AAAACCCCGGGGTTTTAAAA", "CCCCGGGGTTTTAAAACCCC"];
+        let mut input = String::new();
+        for i in 0..6 {
+            let seq = format!("{}{}", &seqs[i % 2][i..], &seqs[0][..i]);
+            input += &record(&format!("u{}", i), &seq);
+        }
+        for i in 0..4 {
+            input += &record(&format!("d{}", i), "GATTACAGATTACAGATTAC");
+        }
+        let sample = |target, seed| {
+            let mut s = Subsample::new(target, seed);
+            let (mut out, params) = (Vec::new(), Params::default());
+            for _ in 0..2 {
+                process_reads(
+                    &params,
+                    &mut input.as_bytes(),
+                    &mut out,
+                    None,
+                    Some(&mut s),
+                    None,
+                    None,
+                )
+                .unwrap();
+                s.start_second_pass();
+            }
+            String::from_utf8(out).unwrap()
+        };
+        let seqs = |out: &str| {
+            let seqs: Vec<_> = out.lines().skip(1).step_by(4).collect();
+            (seqs.len(), seqs.iter().collect::<HashSet<_>>().len())
+        };
+        let out = sample(5, 1);
+        assert_eq!(seqs(&out), (5, 5));
+        assert_eq!(sample(5, 1), out);
+        assert_ne!(sample(5, 2), out, "a new seed, a new sample");
+
+        // every sequence once, then two more of the duplicates
+        let out = sample(9, 1);
+        assert_eq!(seqs(&out), (9, 7));
+        assert!(out.starts_with("@u0\n"));
+    }
+
+    #[test]
+    fn recal_table_maps_output_qualities() {
+        let path = std::env::temp_dir().join("adapto-rs-test-recal.txt");
+        std::fs::write(&path, "# observed adjusted\n40 30\n\n2 0\n").unwrap();
+        let table = read_recal_table(path.to_str().unwrap()).unwrap();
+        assert_eq!(table[b'I' as usize], b'?');
+        assert_eq!(table[b'#' as usize], b'!');
+        assert_eq!(table[b'5' as usize], b'5');
+
+        let params = Params {
+            recal: Some(table),
+            ..Default::default()
+        };
+        let seq = "ACGTTGCAACGTTGCTACGT";
+        let input = format!("@r1\n{}\n+\n{}5\n", seq, "I".repeat(19));
+        let (out, _) = trim(&params, input.as_bytes());
+        let expected = format!("@r1\n{}\n+\n{}5\n", seq, "?".repeat(19));
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+        std::fs::write(&path, "40 30 20\n").unwrap();
+        let err = read_recal_table(path.to_str().unwrap()).unwrap_err();
+        assert!(err.to_string().starts_with("bad line in"));
+        std::fs::write(&path, "40 94\n").unwrap();
+        assert!(read_recal_table(path.to_str().unwrap()).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reads_are_put_in_the_signature_orientation() {
+        let params = Params {
+            orient: b"GATTACA".to_vec(),
+            ..Default::default()
+        };
+        // the signature, its reverse complement, and neither
+        let seqs = [
+            "CCGGGATTACACCTTGCAGG",
+            "CCTGCAAGGTGTAATCCCGG",
+            "CCTGCAAGGTGCAATCCCGG",
+        ];
+        let qual = format!("?{}", "I".repeat(19));
+        let input: String = seqs
+            .iter()
+            .map(|x| format!("@r\n{}\n+\n{}\n", x, qual))
+            .collect();
+        let (out, stats) = trim(&params, input.as_bytes());
+        let flipped = format!("{}?", "I".repeat(19));
+        let expected =
+            [(seqs[0], &qual), (seqs[0], &flipped), (seqs[2], &qual)]
+                .map(|(seq, qual)| format!("@r\n{}\n+\n{}\n", seq, qual))
+                .concat();
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+        assert_eq!(stats.flipped, 1);
+    }
+
+    #[test]
+    fn reads_are_split_by_lane() {
+        assert_eq!(lane_from_name(b"M1:7:FC1:2:1101:10:20 1:N:0:1"), Some("2"));
+        assert_eq!(lane_from_name(b"HWI:3:5:10:20#0/1"), Some("3"));
+        assert_eq!(lane_from_name(b"SRR1.1 HWI:3:5:10:20"), None);
+        assert_eq!(lane_from_name(b"M1:7:FC1:x:1101:10:20"), None);
+        let path = lane_path("dir/out.fq.gz", "2");
+        assert_eq!(path, std::path::Path::new("dir/out_L002.fq.gz"));
+
+        let dir = std::env::temp_dir().join("adapto-rs-test-lanes");
+        std::fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("out.fq");
+        let mut lanes = Lanes {
+            output: output.to_str().unwrap(),
+            lvl: CompLvl::Uncompressed,
+            codec: Codec::Gzip,
+            tpool: None,
+            writers: HashMap::new(),
+        };
+        let seq = "ACGTTGCAACGTTGCAACGT";
+        let names = ["M1:7:FC1:1:1:1:1", "M1:7:FC1:2:1:1:2", "r3"];
+        let reads: Vec<_> = names.iter().map(|x| record(x, seq)).collect();
+        let (mut out, params) = (Vec::new(), Params::default());
+        process_reads(
+            &params,
+            &mut reads.concat().as_bytes(),
+            &mut out,
+            None,
+            None,
+            Some(&mut lanes),
+            None,
+        )
+        .unwrap();
+        lanes.finish().unwrap();
+        let read = |lane| {
+            let path = lane_path(output.to_str().unwrap(), lane);
+            std::fs::read_to_string(path).unwrap()
+        };
+        assert_eq!(read("1"), reads[0]);
+        assert_eq!(read("2"), reads[1]);
+        assert_eq!(String::from_utf8(out).unwrap(), reads[2]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn duplex_reads_are_split_at_the_hairpin() {
+        let params = Params {
+            hairpin: b"TTTCCCGGGAAA".to_vec(),
+            ..Default::default()
+        };
+        let template = "ACGTTGCAACGTTGCAACGTCCGATG";
+        let complement = "CATCGGACGTTGCAACGTTGCAACGT";
+        let duplex = format!("{}TTTCCCGGGAAA{}", template, complement);
+        let (out, stats) = trim(&params, record("r1", &duplex).as_bytes());
+        let expected = [
+            record("r1 strand=template", template),
+            record("r1 strand=complement", template),
+        ];
+        assert_eq!(String::from_utf8(out).unwrap(), expected.concat());
+        assert_eq!(stats.hairpins, 1);
+        assert_eq!(stats.reads_kept, 1);
+
+        // the strands on each side don't match, so it isn't a hairpin
+        let simplex = format!("{}TTTCCCGGGAAA{}", template, template);
+        let input = record("r2", &simplex);
+        let (out, stats) = trim(&params, input.as_bytes());
+        assert_eq!(String::from_utf8(out).unwrap(), input);
+        assert_eq!(stats.hairpins, 0);
+    }
+
+    #[test]
+    fn front_adaptor_is_trimmed() {
+        let insert = "ACGTTGCAACGTTGCAACGT";
+        let params = Params {
+            front: vec![b"CCTACGGG".to_vec()],
+            ..Default::default()
+        };
+        // inside the read, and only the end of it at the start
+        for prefix in ["TTTCCTACGGG", "ACGGG"] {
+            let input = record("r1", &format!("{}{}", prefix, insert));
+            let (out, stats) = trim(&params, input.as_bytes());
+            assert_eq!(String::from_utf8(out).unwrap(), record("r1", insert));
+            assert_eq!(stats.with_front, 1);
+        }
+
+        // anchored, so only at the very start
+        let params = Params {
+            front: vec![b"^CCTACGGG".to_vec()],
+            ..Default::default()
+        };
+        let input = record("r1", &format!("CCTACGGG{}", insert));
+        let (out, _) = trim(&params, input.as_bytes());
+        assert_eq!(String::from_utf8(out).unwrap(), record("r1", insert));
+        let input = record("r1", &format!("TTTCCTACGGG{}", insert));
+        let (out, stats) = trim(&params, input.as_bytes());
+        assert_eq!(String::from_utf8(out).unwrap(), input);
+        assert_eq!(stats.with_front, 0);
+    }
+
+    #[test]
+    fn linked_adaptors_keep_what_is_between() {
+        let insert = "ACGTTGCAACGTTGCAACGT";
+        let params = Params {
+            linked: vec![(b"CCTACGGG".to_vec(), b"GGATCCAA".to_vec())],
+            ..Default::default()
+        };
+        let input = record("r1", &format!("TTCCTACGGG{}GGATCCAACC", insert));
+        let (out, stats) = trim(&params, input.as_bytes());
+        assert_eq!(String::from_utf8(out).unwrap(), record("r1", insert));
+        assert_eq!(stats.with_linked, 1);
+
+        // both must be found, in order
+        for seq in [
+            format!("TTCCTACGGG{}", insert),
+            format!("{}GGATCCAACC", insert),
+            format!("GGATCCAA{}CCTACGGG", insert),
+        ] {
+            let input = record("r1", &seq);
+            let (out, stats) = trim(&params, input.as_bytes());
+            assert_eq!(String::from_utf8(out).unwrap(), input);
+            assert_eq!(stats.with_linked, 0);
+        }
+    }
+
+    #[test]
+    fn malformed_records_are_rejected_unless_permissive() {
+        let seq = "ACGTTGCAACGTTGCAACGT";
+        let qual = "I".repeat(seq.len());
+        let error = |params: &Params, input: &str| {
+            let mut out = Vec::new();
+            let mut input = input.as_bytes();
+            process_reads(params, &mut input, &mut out, None, None, None, None)
+                .map(|_| ())
+                .unwrap_err()
+                .to_string()
+        };
+        let strict = Params::default();
+        for (input, msg) in [
+            (
+                format!("{}\n{}", record("r1", seq), record("r2", seq)),
+                "blank",
+            ),
+            (format!("@r1\n{}\n+r2\n{}\n", seq, qual), "\"+\" line not"),
+            (format!("@r1\n{}\n-\n{}\n", seq, qual), "no \"+\" line"),
+            (format!("@r1\n{}\n+\n{}I\n", seq, qual), "lengths differ"),
+            (format!("r1\n{}\n+\n{}\n", seq, qual), "malformed record"),
+        ] {
+            assert!(error(&strict, &input).contains(msg), "{}", msg);
+        }
+
+        // blank lines are skipped and the "+" line is written plain
+        let permissive = Params {
+            permissive: true,
+            ..Default::default()
+        };
+        let input = format!(
+            "\n{}\n\n@r2\n{}\n+r2 x\n{}\n",
+            record("r1", seq),
+            seq,
+            qual
+        );
+        let (out, stats) = trim(&permissive, input.as_bytes());
+        let expected = record("r1", seq) + &record("r2", seq);
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+        assert_eq!(stats.blank_lines, 3);
+        assert_eq!(stats.plus_mismatch, 1);
+        let input = format!("@r1\n{}\n+\n{}I\n", seq, qual);
+        assert!(error(&permissive, &input).contains("lengths differ"));
+    }
+
+    #[test]
+    fn kept_mates_go_to_the_unpaired_outputs() {
+        let params = Params {
+            min_length: 15,
+            ..Default::default()
+        };
+        let (long, short) = ("ACGTTGCAACGTTGCAACGT", "ACGTTGCAAGATCGGAAGAGC");
+        let end1 = [("p1/1", short), ("p2/1", long), ("p3/1", long)];
+        let end2 = [("p1/2", long), ("p2/2", short), ("p3/2", long)];
+        let fastq = |recs: &[(&str, &str)]| -> String {
+            recs.iter().map(|(name, seq)| record(name, seq)).collect()
+        };
+        let (end1, end2) = (fastq(&end1), fastq(&end2));
+        let mut out = [(); 5].map(|_| Vec::new());
+        let [out1, out2, discarded, unpaired1, unpaired2] = &mut out;
+        let outputs = PairOutputs {
+            ends: (out1, out2),
+            discarded: Some(discarded),
+            unpaired: (Some(unpaired1), Some(unpaired2)),
+        };
+        let readers = (&mut end1.as_bytes(), &mut end2.as_bytes());
+        let (stats1, stats2, _) =
+            process_pairs(&params, readers, outputs, None, None, None).unwrap();
+        let [out1, out2, discarded, unpaired1, unpaired2] =
+            out.map(|x| String::from_utf8(x).unwrap());
+        assert_eq!(out1, record("p3/1", long));
+        assert_eq!(out2, record("p3/2", long));
+        assert_eq!(unpaired1, record("p2/1", long));
+        assert_eq!(unpaired2, record("p1/2", long));
+        assert!(discarded.contains("@p1/1 reason=too_short\n"));
+        assert!(discarded.contains("@p2/2 reason=too_short\n"));
+        assert!(!discarded.contains("mate_discarded"));
+        assert_eq!((stats1.unpaired, stats2.unpaired), (1, 1));
+    }
+
+    #[test]
+    fn crlf_line_ends_are_accepted() {
+        let input: String = (0..20)
+            .map(|i| record(&format!("r{}", i), "ACGTTGCAACGTAGATCGGAAGAGC"))
+            .collect();
+        let crlf = input.replace('\n', "\r\n");
+        let (expected, _) = trim(&Params::default(), input.as_bytes());
+        // small buffers to split a "\r\n" between reads of the input
+        for buf_sz in [16, 31, 64, 1 << 16] {
+            let params = Params {
+                buf_sz,
+                ..Default::default()
+            };
+            let (out, stats) = trim(&params, crlf.as_bytes());
+            assert_eq!(out, expected, "buffer size {}", buf_sz);
+            assert_eq!(stats.reads, 20);
+        }
+
+        // passthrough writes the input as it was
+        let params = Params {
+            passthrough: true,
+            ..Default::default()
+        };
+        let (out, _) = trim(&params, crlf.as_bytes());
+        assert_eq!(String::from_utf8(out).unwrap(), crlf);
+    }
+
+    #[test]
+    fn concatemers_are_split_at_the_backbone() {
+        let params = Params {
+            concatemer: b"GGATCCAAGG".to_vec(),
+            ..Default::default()
+        };
+        let insert = "ACGTTGCAACGTTGCAACGT";
+        let seq =
+            format!("TTGCAGGATCCAAGG{0}GGATCCAAGG{0}GGATCCAAGGACT", insert);
+        let (out, stats) = trim(&params, record("r1 x", &seq).as_bytes());
+        let expected = [
+            record("r1_1 x", "TTGCA"),
+            record("r1_2 x", insert),
+            record("r1_3 x", insert),
+            record("r1_4 x", "ACT"),
+        ];
+        assert_eq!(String::from_utf8(out).unwrap(), expected.concat());
+        assert_eq!((stats.concatemers, stats.subreads), (1, 4));
+        assert_eq!(stats.written, 4);
+
+        // only part of the backbone at the end, which is removed
+        let seq = format!("{0}GGATCCAAGG{0}GGATC", insert);
+        let (out, _) = trim(&params, record("r2", &seq).as_bytes());
+        let expected = record("r2_1", insert) + &record("r2_2", insert);
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+        // no full copy, so not a concatemer
+        let input = record("r3", &format!("{}GGATC", insert));
+        let (out, stats) = trim(&params, input.as_bytes());
+        assert_eq!(String::from_utf8(out).unwrap(), input);
+        assert_eq!(stats.concatemers, 0);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_dict_output_reads_back() {
+        let path = std::env::temp_dir().join("adapto-rs-test-dict.fq.zst");
+        let codec = Codec::Zstd { dict: true };
+        // too few records to train on gives no dictionary
+        for (n, magic) in [(100, ZSTD_SKIPPABLE_MAGIC), (3, ZSTD_MAGIC)] {
+            let input: String = (0..n)
+                .map(|i| record(&format!("r{}", i), "ACGTTGCAACGTTGCAACGT"))
+                .collect();
+            let mut w = Output::new(&path, CompLvl::Default, codec).unwrap();
+            w.write_all(input.as_bytes()).unwrap();
+            w.finish().unwrap();
+            assert!(std::fs::read(&path).unwrap().starts_with(&magic));
+            let mut out = String::new();
+            let mut r = open_input(path.to_str().unwrap()).unwrap();
+            r.read_to_string(&mut out).unwrap();
+            assert_eq!(out, input);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn interleaved_pairs_are_found_and_kept_together() {
+        let dir = std::env::temp_dir().join("adapto-rs-test-interleaved");
+        std::fs::create_dir_all(&dir).unwrap();
+        let (input, output) = (dir.join("in.fq"), dir.join("out.fq"));
+        let (input, output) =
+            (input.to_str().unwrap(), output.to_str().unwrap());
+        let params = Params {
+            min_length: 15,
+            ..Default::default()
+        };
+        let long = "ACGTTGCAACGTTGCAACGT";
+        let pairs = [
+            record("p1/1", &format!("{}AGATCGGAAGAGC", long)),
+            record("p1/2", long),
+            record("p2/1", long),
+            record("p2/2", "ACGTTGCAAGATCGGAAGAGC"),
+            record("p3/1", long),
+            record("p3/2", long),
+        ];
+        std::fs::write(input, pairs.concat()).unwrap();
+        let trim = |interleaved| {
+            let none = (None, None);
+            let stats = remove_adaptors_interleaved(
+                &params,
+                input,
+                output,
+                None,
+                none,
+                interleaved,
+            )
+            .unwrap();
+            (stats, std::fs::read_to_string(output).unwrap())
+        };
+        let ((stats, pair_stats), out) = trim(None);
+        assert_eq!(stats.len(), 2);
+        assert!(pair_stats.is_some());
+        let expected = [record("p1/1", long), record("p1/2", long)];
+        assert_eq!(out, expected.concat() + &pairs[4] + &pairs[5]);
+
+        // taken as single reads, the short one alone is discarded
+        let ((stats, pair_stats), out) = trim(Some(false));
+        assert_eq!(stats.len(), 1);
+        assert!(pair_stats.is_none());
+        assert_eq!(out.matches('@').count(), 5);
+
+        // reads with names not in pairs are single reads
+        let reads = [record("r1", long), record("r2", long)];
+        std::fs::write(input, reads.concat()).unwrap();
+        let ((stats, _), out) = trim(None);
+        assert_eq!(stats.len(), 1);
+        assert_eq!(out, reads.concat());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pairs_to_one_output_are_interleaved() {
+        let dir = std::env::temp_dir().join("adapto-rs-test-one-output");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |x: &str| dir.join(x).to_str().unwrap().to_string();
+        let (in1, in2, out) = (path("in1.fq"), path("in2.fq"), path("out.fq"));
+        let long = "ACGTTGCAACGTTGCAACGT";
+        let end1 = [record("p1/1", long), record("p2/1", long)];
+        let end2 = [record("p1/2", long), record("p2/2", long)];
+        std::fs::write(&in1, end1.concat()).unwrap();
+        std::fs::write(&in2, end2.concat()).unwrap();
+        let params = Params::default();
+        let none = (None, None);
+        let (stats1, stats2, _) = remove_adaptors_paired(
+            &params,
+            (&in1, &in2),
+            (&out, &out),
+            None,
+            none,
+        )
+        .unwrap();
+        let expected = [&end1[0], &end2[0], &end1[1], &end2[1]];
+        let out = std::fs::read_to_string(&out).unwrap();
+        assert_eq!(out, expected.map(|x| x.as_str()).concat());
+        assert_eq!((stats1.written, stats2.written), (2, 2));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn barcode_read_is_kept_and_tagged() {
+        let (cb, umi) = ("AAAACCCCGGGGTTTT", "ACGTACGTACGT");
+        let insert = "ACGTTGCAACGTTGCAACGT";
+        let r1 = format!("{}{}TTTTTTTTTT", cb, umi);
+        let short = format!("{}ACG", cb);
+        let input1 = [record("p1/1", &r1), record("p2/1", &short)].concat();
+        let r2 = format!("{}AAAAAAAAAAAA", insert);
+        let input2 = [record("p1/2", &r2), record("p2/2", &r2)].concat();
+        let params = Params {
+            adaptors: Vec::new(),
+            adaptors2: Some(vec![b"AGATCGGAAGAGCGTCGTGTAG".to_vec()]),
+            poly_x: Some((b'A', 10)),
+            barcode: Some((cb.len(), umi.len())),
+            ..Params::default()
+        };
+        let [out1, out2, discarded] =
+            trim_pairs(&params, (&input1, &input2)).unwrap();
+        let (q1, q2) = ("I".repeat(cb.len()), "I".repeat(umi.len()));
+        let tags =
+            format!("CR:Z:{}\tCY:Z:{}\tUR:Z:{}\tUY:Z:{}", cb, q1, umi, q2);
+        assert_eq!(out1, record("p1/1", &r1));
+        assert_eq!(out2, record(&format!("p1/2 {}", tags), insert));
+        assert!(discarded.contains("p2/1") && discarded.contains("p2/2"));
+        assert!(discarded.contains("no_barcode"));
+    }
+
+    #[test]
+    fn stringency_can_differ_by_end() {
+        let insert = "ACGTTGCAACGTTGCAACGT";
+        let read = format!("{}AGAT", insert);
+        let input1 = record("p1/1", &read);
+        let input2 = record("p1/2", &read);
+        let short = Stringency {
+            min_overlap: 5,
+            ..Stringency::default()
+        };
+        let params = Params {
+            stringency: [(short, short), Default::default()],
+            ..Params::default()
+        };
+        let [out1, out2, _] = trim_pairs(&params, (&input1, &input2)).unwrap();
+        assert_eq!(out1, input1);
+        assert_eq!(out2, record("p1/2", insert));
+    }
+
+    #[test]
+    fn match_fraction_limits_mismatches() {
+        let insert = "ACGTTGCAACGTTGCAACGT";
+        // two mismatches in 13 bases of the adaptor
+        let input = record("r1", &format!("{}AGTTCGGTAGAGC", insert));
+        let loose = Stringency {
+            frac: 0.8,
+            ..Stringency::default()
+        };
+        for (stringency, expected) in [
+            (Stringency::default(), input.clone()),
+            (loose, record("r1", insert)),
+        ] {
+            let params = Params {
+                max_errors: 2,
+                stringency: [(stringency, stringency); 2],
+                ..Params::default()
+            };
+            let (out, _) = trim(&params, input.as_bytes());
+            assert_eq!(String::from_utf8(out).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn sweep_counts_reads_trimmed_by_stringency() {
+        let insert = "ACGTTGCAACGTTGCAACGT";
+        let input = [
+            record("r1", &format!("{}AG", insert)),
+            record("r2", &format!("{}AGATCGGAAG", insert)),
+            record("r3", insert),
+        ]
+        .concat();
+        let grid = [1, 3].map(|min_overlap| Stringency {
+            min_overlap,
+            ..Stringency::default()
+        });
+        let adaptors = [b"AGATCGGAAGAGC".to_vec()];
+        let (reads, counts) =
+            sweep::sweep_counts(&mut input.as_bytes(), &adaptors, 0, &grid, 10)
+                .unwrap();
+        assert_eq!(reads, 3);
+        assert_eq!(counts, vec![(2, 12), (1, 10)]);
     }
-    process_reads(buf_sz, adaptor, &mut reader, &mut writer, cutoff)
 }
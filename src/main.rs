@@ -24,21 +24,77 @@
  */
 
 /// Program to cut adaptors from sequenced reads. Accepts one adaptor
-/// and will apply it to both ends in paired-end data. Removes Ns at
-/// the end of reads. Removes low quality bases at ends of reads.
-/// Output is compressed as bgzf. Input may be compressed as gz/bgzf
-/// or not. Extra threads help with compressing output and
-/// decompressing input.
-use clap::Parser;
+/// and will apply it to both ends in paired-end data, unless another
+/// is given for the second end. Removes Ns at the end of reads.
+/// Removes low quality bases at ends of reads. Output is compressed
+/// as bgzf. Input may be compressed as gz/bgzf or not. Extra threads
+/// help with compressing output and decompressing input.
+use clap::builder::ArgPredicate;
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
 use file_format::FileFormat;
-use num_cpus;
+#[cfg(feature = "profile")]
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cmp::max;
 use std::error::Error;
+use std::io::IsTerminal;
 use std::str::from_utf8;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed};
+use std::time::{Duration, Instant};
+
+/// The system allocator, but counting allocations for --profile, to
+/// check that trimming does not allocate for each record. Nothing is
+/// counted unless asked for, and allocations in htslib are not seen.
+/// It is only used in a build with the "profile" feature, so other
+/// builds pay nothing for each allocation.
+#[cfg(feature = "profile")]
+struct CountingAlloc;
+
+static COUNTING: AtomicBool = AtomicBool::new(false);
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static FIRST_BATCH_READS: AtomicUsize = AtomicUsize::new(0);
+
+/// Start counting again after the first batch, which sets up buffers
+/// that are then reused, so the count is for the steady state.
+fn restart_count(reads: usize) {
+    ALLOCATIONS.store(0, Relaxed);
+    FIRST_BATCH_READS.store(reads, Relaxed);
+}
+
+#[cfg(feature = "profile")]
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if COUNTING.load(Relaxed) {
+            ALLOCATIONS.fetch_add(1, Relaxed);
+        }
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+    unsafe fn realloc(
+        &self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> *mut u8 {
+        if COUNTING.load(Relaxed) {
+            ALLOCATIONS.fetch_add(1, Relaxed);
+        }
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[cfg(feature = "profile")]
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
-    /// Fastq input file
+    /// Fastq input file, or "-" for standard input
     #[structopt(required = true)]
     fastq: String,
 
@@ -46,8 +102,17 @@ struct Args {
     #[structopt(required = false)]
     pfastq: Option<String>,
 
-    /// Output file
-    #[arg(short, long)]
+    /// Output file, or "-" for standard output
+    #[arg(
+        short,
+        long,
+        required = false,
+        required_unless_present_any = ["pipe_mode", "exec"],
+        default_value_ifs = [
+            ("pipe_mode", ArgPredicate::IsPresent, "-"),
+            ("exec", ArgPredicate::IsPresent, "-"),
+        ]
+    )]
     out: String,
 
     /// Second output file for paired-end reads
@@ -55,82 +120,1097 @@ struct Args {
     #[arg(short, long)]
     pout: Option<String>,
 
-    /// Quality score cutoff
-    #[arg(short, long, default_value_t = 20)]
-    qual_cutoff: u8,
+    /// Take one input as pairs with the two ends interleaved, one
+    /// after the other as from `samtools fastq`, and write them the
+    /// same way; "auto" does this for standard input if the first two
+    /// reads have the same name
+    #[arg(
+        long,
+        value_parser = ["auto", "yes", "no"],
+        default_value = "auto",
+        conflicts_with = "pfastq"
+    )]
+    interleaved: String,
+
+    /// Write the output to standard output for a program to read:
+    /// "bwa" is plain text with the two ends of each pair one after
+    /// the other, as `bwa mem -p` reads it; no -o or -p is given
+    #[arg(
+        long,
+        value_parser = ["bwa"],
+        conflicts_with_all = ["out", "pout", "zip", "output_format"]
+    )]
+    pipe_mode: Option<String>,
+
+    /// Run this shell command with the output as its standard input,
+    /// e.g. 'bwa mem -p ref.fa - > out.sam', and fail if it does; pairs
+    /// are written interleaved, and no -o or -p is given
+    #[arg(long, conflicts_with_all = ["out", "pout"])]
+    exec: Option<String>,
+
+    /// Quality score cutoff; with two, e.g. "15,20", the first is for
+    /// the 5' end and the second for the 3' end
+    #[arg(short, long, value_delimiter = ',', default_value = "20")]
+    qual_cutoff: Vec<u8>,
+
+    /// Quality scores in the input are Phred+64, as from old Illumina
+    /// machines; the output has Phred+33
+    #[arg(long)]
+    phred64: bool,
+
+    /// Skip blank lines between records, and allow text after the "+"
+    /// that is not the read name; both are counted in the report
+    #[arg(long)]
+    permissive: bool,
+
+    /// Quality score cutoff as an error probability, e.g. 0.01 for a
+    /// quality score cutoff of 20
+    #[arg(long, conflicts_with = "qual_cutoff")]
+    error_cutoff: Option<f64>,
+
+    /// Quality score cutoff at the 5' end
+    #[arg(long, visible_alias = "qual-cutoff-5p", default_value_t = 0)]
+    front_cutoff: u8,
+
+    /// Algorithm for quality trimming: cutadapt, a sliding window as
+    /// in Trimmomatic, or modified Mott with the error probability of
+    /// the quality cutoff as its limit
+    #[arg(
+        long,
+        value_parser = ["cutadapt", "window", "mott"],
+        default_value = "cutadapt"
+    )]
+    qual_algorithm: String,
+
+    /// Window size for the sliding window quality trimming
+    #[arg(long, default_value_t = 4)]
+    qual_window: usize,
+
+    /// Adaptor sequence; may be repeated or given as a comma separated
+    /// list, and each read is trimmed at the best match: the fewest
+    /// mismatches, then the longest, then the first in the read
+    #[arg(short, long, value_delimiter = ',', default_value = "AGATCGGAAGAGC")]
+    adaptor: Vec<String>,
+
+    /// Adaptors and settings for a common library kit; -a, -A,
+    /// --min-length and --trim-poly-x override those of the preset. For
+    /// 10x-3prime the first end is the cell barcode and UMI, which is
+    /// kept as it is and added to the second end as CR, CY, UR and UY
+    /// tags; for 10x-atac the barcode is in an index read of its own
+    #[arg(long, value_parser = [
+        "truseq", "nextera", "small-rna", "bgi", "10x-3prime", "10x-atac",
+    ])]
+    preset: Option<String>,
+
+    /// Adaptor sequence for the second end of paired reads, if not the
+    /// same as the first; may be repeated like -a
+    #[arg(short = 'A', long, value_delimiter = ',')]
+    adaptor2: Vec<String>,
+
+    /// Mismatches allowed where an adaptor is found, but never more
+    /// than --frac allows for the bases of the adaptor in the read
+    #[arg(long, default_value_t = 0)]
+    max_errors: usize,
+
+    /// Fewest bases of an adaptor that must be in a read for it to be
+    /// trimmed: for 3' adaptors, then for 5' adaptors if different
+    #[arg(long, value_delimiter = ',', default_value = "1")]
+    min_overlap: Vec<usize>,
+
+    /// The same as --min-overlap for the second end of paired reads,
+    /// if different
+    #[arg(long, value_delimiter = ',')]
+    min_overlap2: Vec<usize>,
+
+    /// Least fraction of the bases of an adaptor in a read that must
+    /// match, with --max-errors: for 3' adaptors, then for 5' adaptors
+    /// if different
+    #[arg(long, value_delimiter = ',', default_value = "0.9")]
+    frac: Vec<f64>,
+
+    /// The same as --frac for the second end of paired reads, if
+    /// different
+    #[arg(long, value_delimiter = ',')]
+    frac2: Vec<f64>,
+
+    /// Name of a field in the read comment, as TAG=SEQ or TAG:Z:SEQ,
+    /// with the adaptor for that read; reads without it use -a
+    #[arg(long)]
+    adaptor_tag: Option<String>,
+
+    /// Adaptor at the 5' end, removed with everything before it; with
+    /// "^" first it must be at the start of the read. May be repeated
+    #[arg(short = 'g', long, value_delimiter = ',')]
+    front: Vec<String>,
+
+    /// Linked adaptors given as FRONT...BACK: only if both are found,
+    /// in order, the read is cut to the part between them. FRONT may
+    /// start with "^" like -g. May be repeated
+    #[arg(long)]
+    linked: Vec<String>,
+
+    /// If the adaptor starts inside a homopolymer, move the trim point
+    /// to its left edge (extend) or its right edge (retract)
+    #[arg(long, value_parser = ["extend", "retract"])]
+    homopolymer_edge: Option<String>,
+
+    /// Trim a tail of this base at the 3' end of reads, after the
+    /// adaptor is removed, e.g. A for poly-A tails
+    #[arg(long, value_parser = ["A", "C", "G", "T"])]
+    trim_poly_x: Option<String>,
 
-    /// Adaptor sequence
-    #[arg(short, long, default_value = "AGATCGGAAGAGC")]
-    adaptor: Option<String>,
+    /// Shortest tail removed by --trim-poly-x
+    #[arg(long, default_value_t = 10)]
+    poly_x_min_run: usize,
+
+    /// Fixed 5' sequence to remove, e.g. a template-switch oligo
+    #[arg(long)]
+    trim_prefix: Option<String>,
+
+    /// Mismatches allowed when matching the 5' prefix
+    #[arg(long, default_value_t = 2)]
+    prefix_mismatches: usize,
+
+    /// Discard or trim reads with the 5' prefix found internally
+    /// followed by poly-C (strand invasion)
+    #[arg(long, value_parser = ["discard", "trim"])]
+    strand_invasion: Option<String>,
+
+    /// Table of observed and adjusted quality scores for output
+    #[arg(long)]
+    recal_table: Option<String>,
+
+    /// Signature sequence; reverse-complement reads that contain it
+    /// on the other strand so all reads have the same orientation
+    #[arg(long)]
+    orient: Option<String>,
+
+    /// Copy the input to the output unchanged, but still report what
+    /// trimming would do
+    #[arg(long)]
+    passthrough: bool,
+
+    /// Check this many reads at the start for signs that the input
+    /// was already trimmed, and warn if so; 0 to not check
+    #[arg(long, default_value_t = 10000)]
+    trimmed_check_reads: usize,
+
+    /// Copy input that looks already trimmed to the output unchanged,
+    /// as with --passthrough, instead of trimming it again
+    #[arg(long)]
+    skip_if_clean: bool,
+
+    /// Write a summary of each input file here
+    #[arg(long)]
+    report: Option<String>,
+
+    /// Write the summary as JSON here, with the bases trimmed for
+    /// each reason, the read lengths and the parameters used
+    #[arg(long)]
+    json: Option<String>,
+
+    /// Write the numbers of reads of each length, before and after
+    /// trimming, to this file as TSV
+    #[arg(long)]
+    length_hist: Option<String>,
+
+    /// Stop after this much time, e.g. "2h", "30m" or "90s", with the
+    /// output so far complete and a report of where to resume
+    #[arg(long)]
+    max_runtime: Option<String>,
+
+    /// Skip this many reads at the start, e.g. those already done by
+    /// a run stopped by --max-runtime; use with --append to resume it
+    #[arg(long, default_value_t = 0)]
+    skip_reads: usize,
+
+    /// Add to the end of existing output files instead of replacing
+    /// them
+    #[arg(long, conflicts_with = "split_by_lane")]
+    append: bool,
+
+    /// Count the records in the outputs at the end and fail if they
+    /// are not the number written, or paired outputs differ
+    #[arg(long, conflicts_with = "split_by_lane")]
+    verify: bool,
+
+    /// Report adaptor and trimming rates for read lengths in bins of
+    /// this width
+    #[arg(long, default_value_t = 0)]
+    length_bin: usize,
+
+    /// Screen the first reads against a reference, given as
+    /// name=file.fa; may be repeated
+    #[arg(long)]
+    screen: Vec<String>,
+
+    /// Number of reads to screen
+    #[arg(long, default_value_t = 10000)]
+    screen_reads: usize,
+
+    /// Check this many reads after trimming for what is left of the
+    /// adaptor, and report how many have some
+    #[arg(long, default_value_t = 0)]
+    remnant_reads: usize,
+
+    /// Write reads from each lane, taken from Illumina read names, to
+    /// a separate output with the lane added to its name
+    #[arg(long)]
+    split_by_lane: bool,
+
+    /// Only trim what is requested: no quality trimming without -q,
+    /// no adaptor trimming without -a and no N trimming without
+    /// --trim-n
+    #[arg(long)]
+    strict_defaults: bool,
+
+    /// Trim Ns at the ends of reads (always done without
+    /// --strict-defaults)
+    #[arg(long)]
+    trim_n: bool,
+
+    /// End reads at the first N inside the read, not only trim Ns at
+    /// the ends
+    #[arg(long)]
+    trim_at_first_n: bool,
+
+    /// End reads at the first N of any W bases that include at least K
+    /// Ns, given as W,K
+    #[arg(long, conflicts_with = "trim_at_first_n")]
+    n_window: Option<String>,
+
+    /// Exit with an error if any of these conditions hold at the end,
+    /// e.g. "adapter_rate>0.5,short_rate>0.3"; metrics are reads,
+    /// adapter_rate, short_rate, discard_rate, kept_rate,
+    /// bases_kept_rate, strand_invasion_rate, flipped_rate, q30_rate,
+    /// q30_rate_kept and remnant_rate
+    #[arg(long)]
+    fail_if: Option<String>,
+
+    /// Hairpin adaptor of duplex long reads; reads with it in the
+    /// middle, between strands that are reverse complements, are split
+    /// into two records, one for each strand
+    #[arg(long)]
+    hairpin: Option<String>,
+
+    /// Backbone or adaptor between copies of the insert in concatemer
+    /// reads, as from rolling-circle amplification; these reads are
+    /// split into one record for each copy, numbered after the name
+    #[arg(long, conflicts_with = "hairpin")]
+    concatemer: Option<String>,
+
+    /// Never take the adaptor to start in this many bases at the start
+    /// of a read, e.g. to protect amplicon primers
+    #[arg(long, default_value_t = 0)]
+    ignore_first: usize,
+
+    /// Amplicon primers in FASTA format; in reads with the adaptor,
+    /// a reverse-complemented primer just before it is also trimmed,
+    /// so only the insert remains
+    #[arg(long)]
+    primers: Option<String>,
+
+    /// Replace low quality bases at the ends of reads with N instead
+    /// of trimming them, so reads keep their length
+    #[arg(long)]
+    mask_quality: bool,
+
+    /// Add where each read was trimmed to its name line, as
+    /// "trimmed=START-STOP/LENGTH" with START and STOP from 0 and the
+    /// base at STOP not kept
+    #[arg(long)]
+    emit_coords: bool,
+
+    /// Print each trimming decision for the read with this name
+    #[arg(long, value_name = "READ_NAME")]
+    explain: Option<String>,
 
     /// Keep all read prefixes (not implemented)
     #[arg(short, long, default_value_t = true)]
     keep_prefix: bool,
 
-    /// Zip output files as BGZF format
+    /// Zip output files as BGZF format; also done if the output file
+    /// name ends in .gz or .bgz, and as zstd if it ends in .zst
     #[arg(short, long)]
     zip: bool,
 
-    /// Threads to use
-    #[arg(short, long, default_value_t = 1)]
-    threads: u32,
+    /// Output format, instead of what -z and the file name say: BGZF,
+    /// gzip as one stream for tools that can't read BGZF, zstd, or
+    /// plain. Inputs in zstd are found from their first bytes; zstd
+    /// needs a build with the zstd feature, which is a default
+    #[arg(
+        long,
+        value_parser = ["gz", "bgzf", "zst", "plain"],
+        conflicts_with = "zip"
+    )]
+    output_format: Option<String>,
+
+    /// Train a dictionary on the first records of each zstd output
+    /// and put it at the start of the file, for better compression of
+    /// names and quality scores; adapto-rs reads these files, but other
+    /// tools need the dictionary given separately
+    #[arg(long, value_parser = ["auto"])]
+    zstd_dict: Option<String>,
+
+    /// Leave out the empty block that ends BGZF output, for consumers
+    /// of a stream that don't expect it
+    #[arg(long)]
+    no_eof_block: bool,
+
+    /// Threads to use [default: the CPUs allowed by SLURM, SGE or
+    /// cgroup limits, or all CPUs]
+    #[arg(short, long)]
+    threads: Option<u32>,
+
+    /// Discard reads shorter than this after trimming
+    #[arg(long, default_value_t = 0)]
+    min_length: usize,
+
+    /// Discard reads with more than this many N bases after trimming,
+    /// or, if below 1, this fraction of the bases
+    #[arg(long)]
+    max_n: Option<f64>,
+
+    /// Discard reads with more expected errors after trimming, which
+    /// is the sum of the error probabilities from quality scores
+    #[arg(long)]
+    max_expected_errors: Option<f64>,
+
+    /// Discard reads with lower complexity after trimming, from 0 to
+    /// 1: the entropy of 3-mers in the read over the most possible
+    #[arg(long, default_value_t = 0.0)]
+    min_complexity: f64,
+
+    /// Write discarded reads here, with the reason in the name
+    #[arg(long)]
+    discarded_output: Option<String>,
+
+    /// Write first ends kept but with their mate discarded here,
+    /// instead of dropping them; these ends are then not also written
+    /// to the discarded output
+    #[arg(long, requires = "pfastq")]
+    unpaired1: Option<String>,
+
+    /// Write second ends kept but with their mate discarded here
+    #[arg(long, requires = "pfastq")]
+    unpaired2: Option<String>,
+
+    /// Keep at most this many reads, chosen at random from the whole
+    /// input, preferring unique sequences
+    #[arg(long)]
+    subsample: Option<usize>,
 
-    /// Buffer size for reading input
-    #[arg(short, long, default_value_t = 256*1024)]
+    /// Seed for all that is chosen at random in a run, which so far is
+    /// the reads kept by --subsample; it is recorded in the reports so
+    /// a run can be repeated exactly
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Starting buffer size for reading input; the buffer grows when
+    /// records do not fit or too few fit to keep the threads busy
+    #[arg(short, long, default_value_t = 64*1024)]
     buffer_size: usize,
 
+    /// Largest size the input buffer can grow to
+    #[arg(long, default_value_t = 64*1024*1024)]
+    max_buffer_size: usize,
+
+    /// Fewest pairs processed together in one task; the two ends of a
+    /// pair are always in the same task
+    #[arg(long, default_value_t = 1)]
+    pair_batch_size: usize,
+
+    /// Show a live dashboard of progress on the terminal; without a
+    /// terminal for stderr, e.g. in a batch job, this does nothing
+    #[arg(long)]
+    tui: bool,
+
+    /// Report the heap allocations made while trimming, after the
+    /// first batch; there should be about as many for any number of
+    /// reads. Needs a build with --features profile
+    #[arg(long)]
+    profile: bool,
+
     /// Be verbose
     #[arg(short, long)]
     verbose: bool,
 }
 
-fn is_readable(filename: &String) -> bool {
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run on built-in examples and check the results
+    SelfTest,
+    /// Compare two reports written with --json
+    ReportDiff {
+        /// Report from before the change
+        before: String,
+        /// Report from after the change
+        after: String,
+        /// Smallest relative change to show
+        #[arg(long, default_value_t = 0.01)]
+        min_change: f64,
+    },
+    /// Compare trimmed reads with those from another trimmer, matching
+    /// reads by name
+    Compare {
+        /// Reads trimmed by this program
+        trimmed: String,
+        /// Reads trimmed by the other program, e.g. cutadapt
+        #[arg(long)]
+        other: String,
+        /// Number of reads that differ to list
+        #[arg(long, default_value_t = 10)]
+        show: usize,
+    },
+    /// Find the fraction of reads a 3' adaptor would be trimmed from
+    /// for each combination of minimum overlap and match fraction,
+    /// without writing any reads
+    Sweep {
+        /// Reads to check
+        fastq: String,
+        /// Adaptor sequence; may be repeated or given as a comma
+        /// separated list
+        #[arg(
+            short,
+            long,
+            value_delimiter = ',',
+            default_value = "AGATCGGAAGAGC"
+        )]
+        adaptor: Vec<String>,
+        /// Minimum overlaps, as FIRST..LAST or one value
+        #[arg(long, default_value = "1..10")]
+        min_overlap: String,
+        /// Match fractions, as FIRST..LAST in steps of --frac-step or
+        /// one value
+        #[arg(long, default_value = "0.8..0.95")]
+        frac: String,
+        /// Step between match fractions
+        #[arg(long, default_value_t = 0.05)]
+        frac_step: f64,
+        /// Mismatches allowed, at most, where --frac allows them
+        #[arg(long, default_value_t = 3)]
+        max_errors: usize,
+        /// Number of reads to check, from the start of the input
+        #[arg(long, default_value_t = 100000)]
+        reads: usize,
+    },
+}
+
+/// The CPU limit from cgroups, either v2 (cpu.max) or v1
+/// (cpu.cfs_quota_us and cpu.cfs_period_us), rounded up.
+fn cgroup_cpus() -> Option<u32> {
+    let read = |f: &str| std::fs::read_to_string(f).ok();
+    let (quota, period) = match read("/sys/fs/cgroup/cpu.max") {
+        Some(x) => {
+            let mut fields = x.split_whitespace();
+            (fields.next()?.to_string(), fields.next()?.to_string())
+        }
+        None => (
+            read("/sys/fs/cgroup/cpu/cpu.cfs_quota_us")?,
+            read("/sys/fs/cgroup/cpu/cpu.cfs_period_us")?,
+        ),
+    };
+    // "max" or -1 means no limit
+    let quota: f64 = quota.trim().parse().ok().filter(|&x| x > 0.0)?;
+    let period: f64 = period.trim().parse().ok().filter(|&x| x > 0.0)?;
+    Some((quota / period).ceil() as u32)
+}
+
+/// The number of threads to use if not given: what the job scheduler
+/// allocated (SLURM or SGE), or the cgroup CPU limit, but never more
+/// than the number of CPUs.
+fn default_threads() -> u32 {
+    let cpus = num_cpus::get() as u32;
+    let from_env = ["SLURM_CPUS_PER_TASK", "NSLOTS"]
+        .iter()
+        .find_map(|x| std::env::var(x).ok()?.trim().parse::<u32>().ok());
+    match from_env.or_else(cgroup_cpus) {
+        Some(x) if x > 0 => x.min(cpus),
+        _ => cpus,
+    }
+}
+
+/// A time span as a number with an optional unit: "s" (the
+/// default), "m", "h" or "d".
+fn parse_duration(text: &str) -> Result<Duration, Box<dyn Error>> {
+    let (num, unit) = match text.find(|x: char| x.is_ascii_alphabetic()) {
+        Some(i) => text.split_at(i),
+        None => (text, "s"),
+    };
+    let secs = match unit {
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        "d" => 86400.0,
+        _ => return Err(format!("bad time unit: {}", text))?,
+    };
+    match num.parse::<f64>() {
+        Ok(x) if x >= 0.0 => Ok(Duration::from_secs_f64(x * secs)),
+        _ => Err(format!("bad time: {}", text))?,
+    }
+}
+
+/// A setting for 3' adaptors and then one for 5' adaptors, which is
+/// the same if not given.
+fn ends<T: Copy>(x: &[T], name: &str) -> Result<(T, T), Box<dyn Error>> {
+    match x {
+        [a] => Ok((*a, *a)),
+        [a, b] => Ok((*a, *b)),
+        _ => Err(format!("--{} takes one or two values", name))?,
+    }
+}
+
+/// The values in a range "FIRST..LAST", both included, in steps of
+/// `step`, or the one value given.
+fn range_values(text: &str, step: f64) -> Result<Vec<f64>, Box<dyn Error>> {
+    let (first, last) = match text.split_once("..") {
+        Some((a, b)) => (a.parse::<f64>()?, b.parse::<f64>()?),
+        None => (text.parse::<f64>()?, text.parse::<f64>()?),
+    };
+    if last < first || step <= 0.0 {
+        Err(format!("not a range: {}", text))?;
+    }
+    // rounded, so that a step of 0.05 gives 0.85 and not 0.8500001
+    let n = ((last - first) / step + 1e-9) as usize;
+    let round = |x: f64| (x * 1e6).round() / 1e6;
+    Ok((0..=n).map(|i| round(first + i as f64 * step)).collect())
+}
+
+/// Run `f` in `pool` if there is one, and otherwise in the global
+/// pool.
+fn in_pool<T: Send>(
+    pool: &Option<rayon::ThreadPool>,
+    f: impl FnOnce() -> Result<T, Box<dyn Error>> + Send,
+) -> Result<T, Box<dyn Error>> {
+    match pool {
+        // errors as text, since they must be sent out of the pool
+        Some(p) => Ok(p.install(|| f().map_err(|e| e.to_string()))?),
+        None => f(),
+    }
+}
+
+fn is_readable(filename: &str) -> bool {
     use std::fs::File;
-    let mut f = match File::open(&filename) {
+    // standard input, which can only be tried by reading it
+    if filename == "-" {
+        return true;
+    }
+    let f = match File::open(filename) {
         Ok(file) => file,
         _ => return false,
     };
-    let mut byte = [0_u8];
+    // an empty file is readable: the output will just be empty
     use std::io::Read;
-    match f.read_exact(&mut byte) {
-        Ok(_) => true,
-        Err(_) => false,
+    f.take(1).read_to_end(&mut Vec::new()).is_ok()
+}
+
+/// A command run with --exec, which reads the output through a pipe.
+/// If it is dropped without `finish`, as when trimming fails, its
+/// input is still closed and it is waited for, so it is not left
+/// running.
+#[cfg(unix)]
+struct Exec {
+    command: String,
+    child: std::process::Child,
+    input: Option<std::process::ChildStdin>,
+}
+
+#[cfg(unix)]
+impl Exec {
+    fn spawn(command: &str) -> Result<Exec, Box<dyn Error>> {
+        let mut child = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(std::process::Stdio::piped())
+            .spawn()?;
+        Ok(Exec {
+            command: command.to_string(),
+            input: child.stdin.take(),
+            child,
+        })
+    }
+
+    /// A path for the pipe, to open as an output file.
+    fn path(&self) -> String {
+        use std::os::fd::AsRawFd;
+        let fd = self.input.as_ref().map_or(-1, |x| x.as_raw_fd());
+        format!("/dev/fd/{}", fd)
+    }
+
+    /// Close the input of the command and wait for it to finish.
+    fn finish(&mut self) -> std::io::Result<std::process::ExitStatus> {
+        self.input = None;
+        self.child.wait()
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Exec {
+    fn drop(&mut self) {
+        let _ = self.finish();
     }
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let args = Args::parse();
+    let matches = Command::augment_subcommands(Args::command()).get_matches();
+    if matches.subcommand().is_some() {
+        return match Command::from_arg_matches(&matches)? {
+            Command::SelfTest => adapto_rs::self_test(),
+            Command::ReportDiff {
+                before,
+                after,
+                min_change,
+            } => adapto_rs::report_diff(&before, &after, min_change),
+            Command::Compare {
+                trimmed,
+                other,
+                show,
+            } => adapto_rs::compare_trimmed(&trimmed, &other, show),
+            Command::Sweep {
+                fastq,
+                adaptor,
+                min_overlap,
+                frac,
+                frac_step,
+                max_errors,
+                reads,
+            } => {
+                let overlaps = range_values(&min_overlap, 1.0)?;
+                if overlaps.iter().any(|&x| x < 1.0 || x.fract() != 0.0) {
+                    Err("minimum overlaps must be positive whole numbers")?;
+                }
+                let fracs = range_values(&frac, frac_step)?;
+                if fracs.iter().any(|x| !(0.0..=1.0).contains(x)) {
+                    Err("match fractions must be between 0 and 1")?;
+                }
+                let mut adaptors = Vec::new();
+                for x in &adaptor {
+                    adapto_rs::check_adaptor(x.as_bytes())?;
+                    adaptors.push(x.as_bytes().to_vec());
+                }
+                let overlaps: Vec<usize> =
+                    overlaps.into_iter().map(|x| x as usize).collect();
+                adapto_rs::sweep(
+                    &fastq, &adaptors, max_errors, &overlaps, &fracs, reads,
+                )
+            }
+        };
+    }
+    let mut args = Args::from_arg_matches(&matches)?;
+    // both ends of pairs go to one stream, which then has them
+    // interleaved
+    let piped = args.pipe_mode.is_some() || args.exec.is_some();
+    if piped && args.pfastq.is_some() {
+        args.pout = Some(args.out.clone());
+    }
+    let given =
+        |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
 
-    if args.threads <= 0 {
-        return Err("number of threads must be positive")?;
+    // with strict defaults, only trim what is asked for explicitly
+    let strict = args.strict_defaults;
+    let (front_cutoff, qual_cutoff) = match args.qual_cutoff[..] {
+        [x] => (args.front_cutoff, x),
+        [x, y] if !given("front_cutoff") => (x, y),
+        [_, _] => {
+            return Err("5' cutoff given with both -q and --front-cutoff")?
+        }
+        _ => return Err("expected one or two quality score cutoffs")?,
+    };
+    let qual_cutoff = match (args.error_cutoff, strict) {
+        (Some(p), _) if p <= 0.0 || p > 1.0 => {
+            return Err("error cutoff must be in (0, 1]")?;
+        }
+        // Phred score for the probability, to the nearest integer
+        (Some(p), _) => (-10.0 * p.log10()).round().min(93.0) as u8,
+        (None, true) if !given("qual_cutoff") => 0,
+        (None, _) => qual_cutoff,
+    };
+    let n_window = match (&args.n_window, args.trim_at_first_n) {
+        (Some(x), _) => {
+            let parsed = x
+                .split_once(',')
+                .and_then(|(w, k)| Some((w.parse().ok()?, k.parse().ok()?)));
+            match parsed {
+                Some((w, k)) if w >= k && k > 0 => Some((w, k)),
+                _ => return Err(format!("bad N window: {}", x))?,
+            }
+        }
+        (None, true) => Some((1, 1)),
+        (None, false) => None,
+    };
+    let trim_n = !strict || args.trim_n || n_window.is_some();
+
+    let threads = args.threads.unwrap_or_else(default_threads);
+    if threads == 0 {
+        Err("number of threads must be positive")?;
     }
 
-    if args.buffer_size <= 0 {
-        return Err("buffer size must be positive")?;
+    if args.buffer_size == 0 {
+        Err("buffer size must be positive")?;
+    }
+    if args.qual_window == 0 {
+        Err("quality window size must be positive")?;
+    }
+    if args.max_buffer_size < args.buffer_size {
+        Err("max buffer size must be at least the buffer size")?;
+    }
+    if args.pair_batch_size == 0 {
+        Err("pair batch size must be positive")?;
+    }
+    if args.max_n.is_some_and(|x| x.is_nan() || x < 0.0) {
+        Err("max N must not be negative")?;
+    }
+    if args
+        .max_expected_errors
+        .is_some_and(|x| x.is_nan() || x < 0.0)
+    {
+        Err("max expected errors must not be negative")?;
+    }
+    if !(0.0..=1.0).contains(&args.min_complexity) {
+        Err("min complexity must be in [0, 1]")?;
     }
 
-    let adaptor = args.adaptor.unwrap().into_bytes();
+    // compress the output if asked or if the file name says to, but
+    // a compressed file with a name that says otherwise is suspicious
+    let zipped_name = |x: &String| x.ends_with(".gz") || x.ends_with(".bgz");
+    let zstd_name = |x: &String| x.ends_with(".zst");
+    let zip = match args.output_format.as_deref() {
+        Some(x) => x != "plain",
+        None => args.zip || zipped_name(&args.out) || zstd_name(&args.out),
+    };
+    let zstd = match args.output_format.as_deref() {
+        Some(x) => x == "zst",
+        None => zstd_name(&args.out),
+    };
+    if zstd && !cfg!(feature = "zstd") {
+        Err("zstd output needs a build with --features zstd")?;
+    }
+    if args.zstd_dict.is_some() && !zstd {
+        Err("--zstd-dict needs zstd output")?;
+    }
+    if args.profile && !cfg!(feature = "profile") {
+        Err("--profile needs a build with --features profile")?;
+    }
+    let outputs = [Some(&args.out), args.pout.as_ref()];
+    for x in outputs.into_iter().flatten() {
+        if zstd && !zstd_name(x) {
+            eprintln!("WARNING: zstd output without .zst name: {}", x);
+        } else if zip && !zstd && !zipped_name(x) {
+            eprintln!("WARNING: compressed output without .gz name: {}", x);
+        } else if !zip && (zipped_name(x) || zstd_name(x)) {
+            eprintln!("WARNING: plain output with compressed name: {}", x);
+        }
+    }
+
+    let fail_if = match &args.fail_if {
+        Some(x) => Condition::parse_list(x)?,
+        None => Vec::new(),
+    };
+
+    // an empty adaptor, e.g. -a "", means no adaptor
+    let sequences = |x: &Vec<String>| -> Vec<Vec<u8>> {
+        x.iter()
+            .filter(|x| !x.is_empty())
+            .map(|x| x.as_bytes().to_vec())
+            .collect()
+    };
+
+    use adapto_rs::{
+        already_trimmed, append_output, check_adaptor, count_records,
+        read_primers, read_recal_table, remove_adaptors,
+        remove_adaptors_interleaved, remove_adaptors_paired, Condition,
+        Cutadapt, HomopolymerEdge, Invasion, Mott, PairStats, Params,
+        QualityTrimmer, Screen, Stats, Stringency, Window, write_json_report,
+        PRESETS,
+    };
+
+    let preset = args
+        .preset
+        .as_ref()
+        .and_then(|x| PRESETS.iter().find(|p| p.name == x));
+    let preset_seqs = |x: &[&str]| -> Vec<Vec<u8>> {
+        x.iter().map(|x| x.as_bytes().to_vec()).collect()
+    };
+    let adaptors = match (preset, strict) {
+        _ if given("adaptor") => sequences(&args.adaptor),
+        (Some(p), _) => preset_seqs(p.adaptors),
+        (None, true) => Vec::new(),
+        (None, false) => sequences(&args.adaptor),
+    };
+    let adaptors2 = match preset {
+        _ if !args.adaptor2.is_empty() => Some(sequences(&args.adaptor2)),
+        Some(p) if !p.adaptors2.is_empty() && args.pfastq.is_some() => {
+            Some(preset_seqs(p.adaptors2))
+        }
+        _ => None,
+    };
+    let min_length = match preset {
+        Some(p) if !given("min_length") => p.min_length,
+        _ => args.min_length,
+    };
+    if adaptors2.is_some() && args.pfastq.is_none() {
+        Err("second adaptor requires paired-end input")?;
+    }
+    let front = sequences(&args.front);
+    let mut linked = Vec::new();
+    for x in &args.linked {
+        match x.split_once("...") {
+            Some((a, b)) if !a.is_empty() && !b.is_empty() => {
+                linked.push((a.as_bytes().to_vec(), b.as_bytes().to_vec()))
+            }
+            _ => return Err(format!("expected FRONT...BACK: {}", x))?,
+        }
+    }
+    // the "^" of an anchored 5' adaptor is not part of its sequence
+    let fronts = front.iter().chain(linked.iter().map(|(a, _)| a));
+    let fronts = fronts.map(|x| x.strip_prefix(b"^").unwrap_or(x));
+    let backs = adaptors.iter().chain(adaptors2.iter().flatten());
+    let backs = backs.chain(linked.iter().map(|(_, b)| b));
+    for x in backs.map(|x| x.as_slice()).chain(fronts) {
+        for w in check_adaptor(x)? {
+            eprintln!("WARNING: {}", w);
+        }
+    }
+
+    let hp_edge = match args.homopolymer_edge.as_deref() {
+        Some("extend") => Some(HomopolymerEdge::Extend),
+        Some(_) => Some(HomopolymerEdge::Retract),
+        None => None,
+    };
+
+    let poly_x = match &args.trim_poly_x {
+        Some(_) if args.poly_x_min_run == 0 => {
+            return Err("poly-X minimum run must be positive")?;
+        }
+        Some(x) => Some((x.as_bytes()[0], args.poly_x_min_run)),
+        None => preset.and_then(|p| p.poly_x),
+    };
+    let barcode = preset.and_then(|p| p.barcode);
+
+    // the second end has the settings of the first unless given
+    let overlap = ends(&args.min_overlap, "min-overlap")?;
+    let frac = ends(&args.frac, "frac")?;
+    let overlap2 = match args.min_overlap2.is_empty() {
+        true => overlap,
+        false => ends(&args.min_overlap2, "min-overlap2")?,
+    };
+    let frac2 = match args.frac2.is_empty() {
+        true => frac,
+        false => ends(&args.frac2, "frac2")?,
+    };
+    let overlaps = [overlap.0, overlap.1, overlap2.0, overlap2.1];
+    if overlaps.contains(&0) {
+        Err("minimum overlap must be positive")?;
+    }
+    let fracs = [frac.0, frac.1, frac2.0, frac2.1];
+    if fracs.iter().any(|x| !(0.0..=1.0).contains(x)) {
+        Err("match fraction must be between 0 and 1")?;
+    }
+    let given2 = !args.min_overlap2.is_empty() || !args.frac2.is_empty();
+    if given2 && args.pfastq.is_none() {
+        Err("second end stringency requires paired-end input")?;
+    }
+    let stringency = [(overlap, frac), (overlap2, frac2)].map(|(o, f)| {
+        (
+            Stringency {
+                min_overlap: o.0,
+                frac: f.0,
+            },
+            Stringency {
+                min_overlap: o.1,
+                frac: f.1,
+            },
+        )
+    });
+
+    let invasion = match args.strand_invasion.as_deref() {
+        Some(_) if args.trim_prefix.is_none() => {
+            return Err("strand invasion filter requires --trim-prefix")?;
+        }
+        Some("discard") => Some(Invasion::Discard),
+        Some(_) => Some(Invasion::Trim),
+        None => None,
+    };
 
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(args.threads as usize)
-        .build_global()
-        .unwrap();
+    let algorithm = args.qual_algorithm.as_str();
+    let qual_trimmer: Box<dyn QualityTrimmer> = match algorithm {
+        "cutadapt" => Box::new(Cutadapt {
+            front: front_cutoff,
+            back: qual_cutoff,
+        }),
+        "window" => Box::new(Window {
+            size: args.qual_window,
+            front: front_cutoff,
+            cutoff: qual_cutoff,
+        }),
+        "mott" if front_cutoff > 0 => {
+            return Err("the mott quality trimming algorithm has no 5' cutoff")?
+        }
+        "mott" => Box::new(Mott {
+            limit: 10f64.powf(-(qual_cutoff as f64) / 10.0),
+        }),
+        x => return Err(format!("unknown quality trimming algorithm: {}", x))?,
+    };
+
+    // the global pool may have been set up already, e.g. by a program
+    // using this as a library, and then a pool of our own is used
+    let global = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads as usize)
+        .build_global();
+    let pool = match global {
+        Ok(()) => None,
+        Err(_) => Some(
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(threads as usize)
+                .build()?,
+        ),
+    };
 
     if args.verbose {
         eprintln!("input file: {}", args.fastq);
-        eprintln!("input file format: {}", FileFormat::from_file(&args.fastq)?);
+        if args.fastq != "-" {
+            let format = FileFormat::from_file(&args.fastq)?;
+            eprintln!("input file format: {}", format);
+        }
         eprintln!("output file: {}", args.out);
-        eprintln!("quality score cutoff: {}", args.qual_cutoff);
-        eprintln!("adaptor sequence: {}", from_utf8(&adaptor)?);
+        if let Some(x) = &args.exec {
+            eprintln!("exec: {}", x);
+        }
+        eprintln!("strict defaults: {}", strict);
+        if let Some(x) = &args.preset {
+            eprintln!("preset: {}", x);
+        }
+        eprintln!("quality score cutoff: {}", qual_cutoff);
+        eprintln!("5' quality score cutoff: {}", front_cutoff);
+        eprintln!("quality trimming algorithm: {}", args.qual_algorithm);
+        eprintln!("mask low quality ends: {}", args.mask_quality);
+        eprintln!("trim Ns at ends: {}", trim_n);
+        if let Some((w, k)) = n_window {
+            eprintln!("end reads at {} Ns in {} bases", k, w);
+        }
+        for x in &adaptors {
+            eprintln!("adaptor sequence: {}", from_utf8(x)?);
+        }
+        for x in adaptors2.iter().flatten() {
+            eprintln!("second end adaptor sequence: {}", from_utf8(x)?);
+        }
+        eprintln!("adaptor mismatches: {}", args.max_errors);
+        let n_ends = if args.pfastq.is_some() { 2 } else { 1 };
+        for (i, (back, front)) in stringency.iter().take(n_ends).enumerate() {
+            eprintln!(
+                "end {} min overlap and match fraction: 3' {} {}, 5' {} {}",
+                i + 1,
+                back.min_overlap,
+                back.frac,
+                front.min_overlap,
+                front.frac
+            );
+        }
+        if let Some(x) = &args.adaptor_tag {
+            eprintln!("adaptor from comment field: {}", x);
+        }
+        for x in &front {
+            eprintln!("5' adaptor sequence: {}", from_utf8(x)?);
+        }
+        for x in &args.linked {
+            eprintln!("linked adaptors: {}", x);
+        }
+        if let Some(x) = &args.homopolymer_edge {
+            eprintln!("homopolymer edge: {}", x);
+        }
+        if let Some(x) = &args.trim_poly_x {
+            eprintln!("poly-{} tail of at least {}", x, args.poly_x_min_run);
+        }
+        eprintln!("ignore first bases for adaptor: {}", args.ignore_first);
+        if let Some(x) = &args.trim_prefix {
+            eprintln!("trim prefix: {}", x);
+            eprintln!("prefix mismatches: {}", args.prefix_mismatches);
+        }
+        if let Some(x) = &args.strand_invasion {
+            eprintln!("strand invasion: {}", x);
+        }
         eprintln!("keep prefix: {}", args.keep_prefix);
-        eprintln!("compress output: {}", args.zip);
-        eprintln!("threads requested: {}", args.threads);
+        eprintln!("compress output: {}", zip);
+        if let Some(x) = &args.zstd_dict {
+            eprintln!("zstd dictionary: {}", x);
+        }
+        if args.no_eof_block {
+            eprintln!("no BGZF EOF block: true");
+        }
+        if let Some(x) = &args.output_format {
+            eprintln!("output format: {}", x);
+        }
+        eprintln!("threads: {}", threads);
+        let effective = match &pool {
+            Some(p) => p.current_num_threads(),
+            None => rayon::current_num_threads(),
+        };
+        eprintln!("effective threads: {}", effective);
         eprintln!("detected cpu cores: {}", num_cpus::get());
         eprintln!("buffer size: {}", args.buffer_size);
+        eprintln!("max buffer size: {}", args.max_buffer_size);
+        eprintln!("minimum length: {}", min_length);
+        if let Some(x) = args.max_n {
+            eprintln!("max N: {}", x);
+        }
+        if let Some(x) = args.max_expected_errors {
+            eprintln!("max expected errors: {}", x);
+        }
+        if args.min_complexity > 0.0 {
+            eprintln!("min complexity: {}", args.min_complexity);
+        }
+        if let Some(x) = &args.discarded_output {
+            eprintln!("discarded output file: {}", x);
+        }
+        if let Some(x) = &args.unpaired1 {
+            eprintln!("unpaired output file: {}", x);
+        }
+        if let Some(x) = &args.unpaired2 {
+            eprintln!("unpaired2 output file: {}", x);
+        }
+        if let Some(x) = &args.recal_table {
+            eprintln!("recalibration table: {}", x);
+        }
+        if let Some(x) = &args.hairpin {
+            eprintln!("hairpin adaptor: {}", x);
+        }
+        if let Some(x) = &args.concatemer {
+            eprintln!("concatemer backbone: {}", x);
+        }
+        if let Some(x) = &args.orient {
+            eprintln!("orientation signature: {}", x);
+        }
+        eprintln!("passthrough: {}", args.passthrough);
+        if let Some(x) = &args.report {
+            eprintln!("report file: {}", x);
+        }
+        if let Some(x) = &args.json {
+            eprintln!("JSON report file: {}", x);
+        }
+        if let Some(x) = &args.length_hist {
+            eprintln!("length histogram file: {}", x);
+        }
+        if args.length_bin > 0 {
+            eprintln!("length bin width: {}", args.length_bin);
+        }
+        eprintln!("split by lane: {}", args.split_by_lane);
+        if let Some(x) = &args.fail_if {
+            eprintln!("fail if: {}", x);
+        }
+        for x in &args.screen {
+            eprintln!("screen reference: {}", x);
+        }
+        if let Some(x) = args.subsample {
+            eprintln!("subsample reads: {}", x);
+        }
+        eprintln!("seed: {}", args.seed);
         match (&args.pfastq, &args.pout) {
             (Some(x), Some(y)) => {
                 eprintln!("input2 file: {}", x);
-                eprintln!("input2 file format: {}", FileFormat::from_file(&x)?);
+                if x != "-" {
+                    let format = FileFormat::from_file(x)?;
+                    eprintln!("input2 file format: {}", format);
+                }
                 eprintln!("output2 file: {}", y);
             }
             (Some(_), None) | (None, Some(_)) => {
@@ -140,35 +1220,402 @@ fn main() -> Result<(), Box<dyn Error>> {
         }
     }
 
+    // the subsample is chosen from all the reads, so a run stopped
+    // early would have nothing to write
+    if args.max_runtime.is_some() && args.subsample.is_some() {
+        Err("subsample can't be used with max runtime")?;
+    }
+
+    // standard input can be read only once, and the output written
+    // to standard output can't be read back or added to
+    let stdin = args.fastq == "-" || args.pfastq.as_deref() == Some("-");
+    if args.fastq == "-" && args.pfastq.as_deref() == Some("-") {
+        Err("only one input can be standard input")?;
+    }
+    if stdin && args.subsample.is_some() {
+        Err("subsample can't be used with standard input")?;
+    }
+    // interleaved pairs are looked for only in standard input, unless
+    // asked for
+    let interleaved = match args.interleaved.as_str() {
+        "yes" => Some(true),
+        "no" => Some(false),
+        _ if args.fastq == "-" => None,
+        _ => Some(false),
+    };
+    if barcode.is_some() && args.pfastq.is_none() && interleaved == Some(false)
+    {
+        Err("the barcode read of a preset needs paired-end input")?;
+    }
+    if args.exec.is_some() && !cfg!(unix) {
+        Err("--exec needs a Unix system")?;
+    }
+    let stdout = args.out == "-" || args.pout.as_deref() == Some("-");
+    if stdout && (args.append || args.verify || args.split_by_lane) {
+        Err("append, verify and split by lane need output files")?;
+    }
+
     // ADS: do this 1st so we don't waste time on end2 if end1 is bad
     if !is_readable(&args.fastq) {
-        return Err(format!("input file not readable: {}", args.fastq))?;
+        Err(format!("input file not readable: {}", args.fastq))?;
     }
 
-    use adapto_rs::remove_adaptors;
+    // the limit is from here, since reading the arguments takes no
+    // time to speak of
+    let deadline = match &args.max_runtime {
+        Some(x) => Some(Instant::now() + parse_duration(x)?),
+        None => None,
+    };
 
-    if let (Some(pfastq), Some(pout)) = (args.pfastq, args.pout) {
-        if !is_readable(&pfastq) {
-            return Err(format!("input file not readable: {}", pfastq))?;
+    let mut params = Params {
+        zip,
+        no_eof_block: args.no_eof_block,
+        gzip: args.output_format.as_deref() == Some("gz"),
+        zstd,
+        zstd_dict: args.zstd_dict.is_some(),
+        n_threads: threads,
+        buf_sz: args.buffer_size,
+        max_buf_sz: args.max_buffer_size,
+        pair_batch: args.pair_batch_size,
+        pair_inserts: args.report.is_some() || args.json.is_some(),
+        adaptors,
+        adaptors2,
+        max_errors: args.max_errors,
+        stringency,
+        adaptor_tag: args.adaptor_tag.clone().map(String::into_bytes),
+        front,
+        linked,
+        hp_edge,
+        poly_x,
+        barcode,
+        primers: match &args.primers {
+            Some(x) => read_primers(x)?,
+            None => Vec::new(),
+        },
+        qual_trimmer,
+        min_length,
+        max_n: args.max_n,
+        max_ee: args.max_expected_errors,
+        min_complexity: args.min_complexity,
+        prefix: args.trim_prefix.unwrap_or_default().into_bytes(),
+        prefix_mm: args.prefix_mismatches,
+        invasion,
+        subsample: args.subsample,
+        seed: args.seed,
+        recal: match &args.recal_table {
+            Some(x) => Some(read_recal_table(x)?),
+            None => None,
+        },
+        orient: args.orient.clone().unwrap_or_default().into_bytes(),
+        passthrough: args.passthrough,
+        length_bin: args.length_bin,
+        screen: match args.screen.is_empty() {
+            true => None,
+            false => Some(Screen::new(&args.screen, args.screen_reads)?),
+        },
+        split_by_lane: args.split_by_lane,
+        trim_n,
+        n_window,
+        hairpin: args.hairpin.clone().unwrap_or_default().into_bytes(),
+        concatemer: args.concatemer.clone().unwrap_or_default().into_bytes(),
+        ignore_first: args.ignore_first,
+        mask_quality: args.mask_quality,
+        emit_coords: args.emit_coords,
+        explain: args.explain.clone().map(|x| x.into_bytes()),
+        remnant_reads: args.remnant_reads,
+        phred64: args.phred64,
+        permissive: args.permissive,
+        skip_reads: args.skip_reads,
+        deadline,
+        tui: args.tui && std::io::stderr().is_terminal(),
+        after_first_batch: args.profile.then_some(restart_count),
+        collectors: Vec::new(),
+    };
+
+    let adaptor_given = !params.adaptors.is_empty();
+
+    // trimming again would only make reads shorter for no reason; for
+    // paired reads both ends must look trimmed
+    if adaptor_given
+        && !params.passthrough
+        && !stdin
+        && args.trimmed_check_reads > 0
+    {
+        let n = args.trimmed_check_reads;
+        let adaptors2 = params.adaptors2.as_ref().unwrap_or(&params.adaptors);
+        let mut trimmed =
+            already_trimmed(&params, &params.adaptors, &args.fastq, n)?;
+        if let Some(pfastq) = &args.pfastq {
+            trimmed &= already_trimmed(&params, adaptors2, pfastq, n)?;
         }
-        remove_adaptors(
-            args.zip,
-            args.threads,
-            args.buffer_size,
-            &adaptor,
-            &pfastq,
-            &pout,
-            args.qual_cutoff,
+        match (trimmed, args.skip_if_clean) {
+            (true, true) => {
+                eprintln!("input looks already trimmed; copying unchanged");
+                params.passthrough = true;
+            }
+            (true, false) => eprintln!(
+                "WARNING: input looks already trimmed; see --skip-if-clean"
+            ),
+            (false, _) => (),
+        }
+    }
+    let report = |input: &String, stats: &Stats| {
+        if adaptor_given {
+            for w in stats.warnings() {
+                eprintln!("WARNING: {}: {}", input, w);
+            }
+        }
+        let rate = |what: &str, count: usize| {
+            let frac = count as f64 / max(stats.reads, 1) as f64;
+            eprintln!(
+                "{} in {}: {} of {} ({:.4})",
+                what, input, count, stats.reads, frac
+            );
+        };
+        if invasion.is_some() {
+            rate("strand invasion artifacts", stats.strand_invasion);
+        }
+        if args.orient.is_some() {
+            rate("reads reverse-complemented", stats.flipped);
+        }
+        if args.verbose {
+            eprintln!("final buffer size for {}: {}", input, stats.buf_sz);
+        }
+        if stats.remnant_checked > 0 {
+            let frac = stats.remnants as f64 / stats.remnant_checked as f64;
+            eprintln!(
+                "adaptor remnants in {}: {} of {} ({:.4})",
+                input, stats.remnants, stats.remnant_checked, frac
+            );
+        }
+        for (name, count) in &stats.screen {
+            let frac = *count as f64 / max(stats.screened, 1) as f64;
+            eprintln!(
+                "screen {} in {}: {} of {} ({:.4})",
+                name, input, count, stats.screened, frac
+            );
+        }
+    };
+
+    let mut summaries: Vec<(String, Stats)> = Vec::new();
+    let mut pair_summary: Option<PairStats> = None;
+
+    // with --append, outputs go to temporary files that are added to
+    // the end of the existing outputs when done
+    let tmp = |x: &String| match args.append {
+        true => format!("{}.{}.tmp", x, std::process::id()),
+        false => x.clone(),
+    };
+    let out = tmp(&args.out);
+    let pout = args.pout.as_ref().map(tmp);
+    let discarded = args.discarded_output.as_ref().map(tmp);
+    let unpaired1 = args.unpaired1.as_ref().map(tmp);
+    let unpaired2 = args.unpaired2.as_ref().map(tmp);
+
+    // the command reads the output through a pipe, so it is started
+    // only after all the checks
+    #[cfg(unix)]
+    let exec = match &args.exec {
+        Some(x) => Some(Exec::spawn(x)?),
+        None => None,
+    };
+    #[cfg(unix)]
+    let (out, pout) = match &exec {
+        Some(e) => (e.path(), pout.map(|_| e.path())),
+        None => (out, pout),
+    };
+
+    COUNTING.store(args.profile, Relaxed);
+    if let (Some(pfastq), Some(pout)) = (&args.pfastq, &pout) {
+        if !is_readable(pfastq) {
+            Err(format!("input file not readable: {}", pfastq))?;
+        }
+        let (stats1, stats2, pairs) = in_pool(&pool, || {
+            remove_adaptors_paired(
+                &params,
+                (&args.fastq, pfastq),
+                (&out, pout),
+                discarded.as_deref(),
+                (unpaired1.as_deref(), unpaired2.as_deref()),
+            )
+        })?;
+        COUNTING.store(false, Relaxed);
+        report(&args.fastq, &stats1);
+        report(pfastq, &stats2);
+        summaries.push((args.fastq.clone(), stats1));
+        summaries.push((pfastq.clone(), stats2));
+        pair_summary = Some(pairs);
+    } else if interleaved != Some(false) {
+        let (stats, pairs) = in_pool(&pool, || {
+            remove_adaptors_interleaved(
+                &params,
+                &args.fastq,
+                &out,
+                discarded.as_deref(),
+                (unpaired1.as_deref(), unpaired2.as_deref()),
+                interleaved,
+            )
+        })?;
+        COUNTING.store(false, Relaxed);
+        let inputs = match pairs {
+            Some(_) => {
+                [1, 2].map(|i| format!("{} end {}", args.fastq, i)).to_vec()
+            }
+            None => vec![args.fastq.clone()],
+        };
+        if pairs.is_some() && args.verbose {
+            eprintln!("input has interleaved pairs");
+        }
+        for (input, stats) in inputs.into_iter().zip(stats) {
+            report(&input, &stats);
+            summaries.push((input, stats));
+        }
+        pair_summary = pairs;
+    } else {
+        let stats = in_pool(&pool, || {
+            remove_adaptors(&params, &args.fastq, &out, discarded.as_deref())
+        })?;
+        COUNTING.store(false, Relaxed);
+        report(&args.fastq, &stats);
+        summaries.push((args.fastq.clone(), stats));
+    }
+    #[cfg(unix)]
+    if let Some(mut e) = exec {
+        let status = e.finish()?;
+        if !status.success() {
+            eprintln!("command failed with {}: {}", status, e.command);
+            std::process::exit(status.code().unwrap_or(1));
+        }
+    }
+    if args.profile {
+        let n = ALLOCATIONS.load(Relaxed);
+        let reads = summaries[0].1.reads - FIRST_BATCH_READS.load(Relaxed);
+        eprintln!("allocations after the first batch: {}", n);
+        eprintln!(
+            "allocations per 1000 reads: {:.3}",
+            1000.0 * n as f64 / max(reads, 1) as f64
+        );
+    }
+
+    // a check that nothing was lost, done before any append so each
+    // output has only the records from this run
+    if args.verify {
+        // interleaved pairs have both ends in the one output
+        let one_output = summaries.len() == 2 && pout.is_none();
+        let pout = pout.as_ref().or(one_output.then_some(&out));
+        let outputs = [Some(&out), pout].into_iter().flatten();
+        for ((input, stats), output) in summaries.iter().zip(outputs) {
+            let n = count_records(output)?;
+            let written = match one_output {
+                true => summaries.iter().map(|(_, x)| x.written).sum(),
+                false => stats.written,
+            };
+            if n != written {
+                Err(format!(
+                    "verify: {} records in output for {}, but {} written",
+                    n, input, written
+                ))?;
+            }
+            let discarded: usize = stats.discarded.values().sum();
+            if stats.reads_kept + discarded != stats.reads {
+                Err(format!(
+                    "verify: {} reads in {}, but {} kept and {} discarded",
+                    stats.reads, input, stats.reads_kept, discarded
+                ))?;
+            }
+        }
+        if let [(_, a), (_, b)] = &summaries[..] {
+            if a.written != b.written {
+                Err("verify: paired outputs differ in records")?;
+            }
+        }
+        if args.verbose {
+            eprintln!("verified output record counts");
+        }
+    }
+
+    if args.append {
+        let appended = [
+            (Some(&out), Some(&args.out)),
+            (pout.as_ref(), args.pout.as_ref()),
+            (discarded.as_ref(), args.discarded_output.as_ref()),
+            (unpaired1.as_ref(), args.unpaired1.as_ref()),
+            (unpaired2.as_ref(), args.unpaired2.as_ref()),
+        ];
+        for (from, to) in appended {
+            if let (Some(from), Some(to)) = (from, to) {
+                append_output(from, to)?;
+            }
+        }
+    }
+
+    if let Some(report_file) = &args.report {
+        use std::io::Write;
+        let mut out = std::fs::File::create(report_file)?;
+        writeln!(out, "seed: {}", args.seed)?;
+        for (input, stats) in &summaries {
+            writeln!(out, "input: {}", input)?;
+            write!(out, "{}", stats)?;
+        }
+        if let (Some(pairs), [(a, _), (b, _)]) = (&pair_summary, &summaries[..])
+        {
+            writeln!(out, "input pair: {}, {}", a, b)?;
+            write!(out, "{}", pairs)?;
+        }
+    }
+
+    if let Some(json_file) = &args.json {
+        // each argument with a value, whether given or by default
+        let parameters: Vec<(String, Vec<String>)> = Args::command()
+            .get_arguments()
+            .filter_map(|a| {
+                let id = a.get_id().as_str();
+                let values = matches.get_raw(id)?;
+                let values = values.map(|x| x.to_string_lossy().into_owned());
+                Some((id.to_string(), values.collect()))
+            })
+            .collect();
+        let mut out = std::fs::File::create(json_file)?;
+        write_json_report(
+            &mut out,
+            &parameters,
+            &summaries,
+            pair_summary.as_ref(),
         )?;
     }
 
-    remove_adaptors(
-        args.zip,
-        args.threads,
-        args.buffer_size,
-        &adaptor,
-        &args.fastq,
-        &args.out,
-        args.qual_cutoff,
-    )
+    if let Some(hist_file) = &args.length_hist {
+        use std::io::Write;
+        let mut out = std::fs::File::create(hist_file)?;
+        writeln!(out, "input\tlength\tbefore\tafter")?;
+        for (input, stats) in &summaries {
+            for (len, (before, after)) in stats.lengths.iter().enumerate() {
+                if before + after > 0 {
+                    writeln!(out, "{}\t{}\t{}\t{}", input, len, before, after)?;
+                }
+            }
+        }
+    }
+
+    // conditions that mean something went badly wrong
+    let mut failed = 0;
+    for (input, stats) in &summaries {
+        for c in &fail_if {
+            if let Some(x) = c.holds(stats) {
+                eprintln!("condition {} holds for {}: {}", c.text, input, x);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        Err(format!("failed conditions: {}", failed))?;
+    }
+    // the output is complete as far as it goes, but the run is not
+    if let Some((_, stats)) = summaries.iter().find(|(_, x)| x.stopped) {
+        Err(format!(
+            "stopped at max runtime; resume with --skip-reads {} --append",
+            stats.skipped + stats.reads
+        ))?;
+    }
+    Ok(())
 }
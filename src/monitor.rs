@@ -0,0 +1,226 @@
+/* MIT License
+ *
+ * Copyright (c) 2023-2024 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use crate::Stats;
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Layout};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Gauge, Paragraph};
+use ratatui::{Terminal, TerminalOptions, Viewport};
+use std::io::Stderr;
+use std::time::{Duration, Instant};
+
+/// How often the dashboard is drawn again.
+const REDRAW: Duration = Duration::from_millis(250);
+
+/// Lines of text in the dashboard, which has a progress bar below
+/// them and a border around both.
+const TEXT_LINES: u16 = 4;
+
+/// The parts of processing that time is spent in, for each batch.
+#[derive(Clone, Copy)]
+pub(crate) enum Stage {
+    Read,
+    Trim,
+    Write,
+}
+
+/// Monitor draws a live dashboard on the terminal for --tui, with
+/// throughput, the time spent in each stage, the rate of trimming so
+/// far, and the time left if the size of the input is known. It is
+/// drawn with ratatui in a few lines below the cursor on stderr, and
+/// fitted again to the width of the terminal if that changes, so
+/// logging to stderr should not be mixed with it. The cursor, which
+/// is hidden while drawing, is shown again after the dashboard when
+/// it is finished or dropped, even if trimming fails.
+pub(crate) struct Monitor {
+    terminal: Terminal<CrosstermBackend<Stderr>>,
+    start: Instant,
+    mark: Instant,          // end of the last stage timed
+    drawn: Option<Instant>, // when the dashboard was last drawn
+    total: Option<u64>,     // input bytes, if known
+    bytes: u64,             // input bytes processed
+    stages: [Duration; 3],  // time spent in each stage
+    restored: bool,         // the cursor is back after the dashboard
+}
+
+impl Monitor {
+    /// A dashboard for an input of `total` bytes, if known, or None if
+    /// the terminal can't be drawn on.
+    pub(crate) fn new(total: Option<u64>) -> Option<Monitor> {
+        let backend = CrosstermBackend::new(std::io::stderr());
+        let options = TerminalOptions {
+            viewport: Viewport::Inline(TEXT_LINES + 3),
+        };
+        let terminal = Terminal::with_options(backend, options).ok()?;
+        let now = Instant::now();
+        Some(Monitor {
+            terminal,
+            start: now,
+            mark: now,
+            drawn: None,
+            total,
+            bytes: 0,
+            stages: [Duration::ZERO; 3],
+            restored: false,
+        })
+    }
+
+    /// Count the time since the last stage ended to this stage.
+    pub(crate) fn lap(&mut self, stage: Stage) {
+        let now = Instant::now();
+        self.stages[stage as usize] += now - self.mark;
+        self.mark = now;
+    }
+
+    /// Draw the dashboard after a batch, if it was not drawn too
+    /// recently. The stats are for each end of paired reads.
+    pub(crate) fn update(&mut self, stats: &[&Stats], bytes: u64) {
+        self.bytes = bytes;
+        if self.drawn.is_some_and(|t| t.elapsed() < REDRAW) {
+            return;
+        }
+        self.draw(stats, false);
+    }
+
+    /// Draw the dashboard for the last time, when the input is done,
+    /// and give the terminal back.
+    pub(crate) fn finish(&mut self, stats: &[&Stats]) {
+        self.draw(stats, true);
+        self.restore();
+    }
+
+    /// Put the cursor, shown again, on the line after the dashboard.
+    fn restore(&mut self) {
+        if !self.restored {
+            self.restored = true;
+            let _ = self.terminal.show_cursor();
+            eprintln!();
+        }
+    }
+
+    /// Draw the dashboard, with the cursor on its last line if it is
+    /// the last time, and otherwise hidden.
+    fn draw(&mut self, stats: &[&Stats], last: bool) {
+        let elapsed = self.start.elapsed();
+        let secs = elapsed.as_secs_f64().max(1e-9);
+        let sum = |f: fn(&Stats) -> usize| stats.iter().map(|&x| f(x)).sum();
+        let reads: usize = sum(|x| x.reads);
+        let bases: usize = sum(|x| x.bases);
+        let with_adaptor: usize = sum(|x| x.with_adaptor);
+        let bases_kept: usize = sum(|x| x.bases_kept);
+        let frac = |x: usize, y: usize| x as f64 / y.max(1) as f64;
+
+        let done = match self.total {
+            Some(total) if self.bytes > 0 => {
+                Some((self.bytes as f64 / total as f64).min(1.0))
+            }
+            _ => None,
+        };
+        let eta = match done {
+            Some(done) => {
+                let left = secs * (1.0 - done) / done;
+                format!("ETA {} ({:.0}%)", clock(left), 100.0 * done)
+            }
+            None => "ETA unknown (compressed input)".to_string(),
+        };
+        let busy = self.stages.iter().sum::<Duration>().as_secs_f64();
+        let share =
+            |i: usize| 100.0 * self.stages[i].as_secs_f64() / busy.max(1e-9);
+        let text = [
+            format!("elapsed    {}  {}", clock(secs), eta),
+            format!(
+                "reads      {}  ({:.0} reads/s, {:.1} MB/s)",
+                reads,
+                reads as f64 / secs,
+                self.bytes as f64 / secs / 1e6
+            ),
+            format!(
+                "trimming   {:.4} of reads with adaptor, {:.4} of bases cut",
+                frac(with_adaptor, reads),
+                1.0 - frac(bases_kept, bases)
+            ),
+            format!(
+                "stages     read {:.0}%  trim {:.0}%  write {:.0}%",
+                share(Stage::Read as usize),
+                share(Stage::Trim as usize),
+                share(Stage::Write as usize)
+            ),
+        ];
+        let label = match done {
+            Some(x) => format!("{:.0}%", 100.0 * x),
+            None => "input size unknown".to_string(),
+        };
+
+        let _ = self.terminal.draw(|frame| {
+            let area = frame.area();
+            let block = Block::bordered().title(" adapto-rs ");
+            let [lines, bar] = Layout::vertical([
+                Constraint::Length(TEXT_LINES),
+                Constraint::Length(1),
+            ])
+            .areas(block.inner(area));
+            frame.render_widget(block, area);
+            let text: Vec<Line> = text.into_iter().map(Line::from).collect();
+            frame.render_widget(Paragraph::new(text), lines);
+            let gauge = Gauge::default().ratio(done.unwrap_or(0.0));
+            frame.render_widget(gauge.label(label), bar);
+            if last {
+                let y = area.bottom().saturating_sub(1);
+                frame.set_cursor_position((area.x, y));
+            }
+        });
+        self.drawn = Some(Instant::now());
+    }
+}
+
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
+
+/// Seconds as hours, minutes and seconds.
+fn clock(secs: f64) -> String {
+    let s = secs.round() as u64;
+    format!("{:02}:{:02}:{:02}", s / 3600, s / 60 % 60, s % 60)
+}
+
+/// The size of an input that is plain text, which is then the number
+/// of bytes that will be read from it. A compressed input, or standard
+/// input, has no size that can be compared with the bytes read.
+pub(crate) fn input_size(filename: &str) -> Option<u64> {
+    use std::io::Read;
+    if filename == "-" {
+        return None;
+    }
+    let mut magic = Vec::new();
+    let file = std::fs::File::open(filename).ok()?;
+    file.take(4).read_to_end(&mut magic).ok()?;
+    match magic.first() {
+        Some(b'@') => std::fs::metadata(filename).ok().map(|x| x.len()),
+        _ => None,
+    }
+}
@@ -0,0 +1,222 @@
+/* MIT License
+ *
+ * Copyright (c) 2023-2024 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+/// A way to find the low quality ends of a read. Given the quality
+/// scores of a read, as in the FASTQ file, it gives the start and
+/// stop of the part of the read to keep. Implement this to use a
+/// quality model other than those here.
+pub trait QualityTrimmer: Send + Sync {
+    fn trim(&self, qual: &[u8]) -> (usize, usize);
+}
+
+/// The cutadapt algorithm, with a cutoff for each end; a cutoff of 0
+/// means that end is not trimmed.
+pub struct Cutadapt {
+    pub front: u8,
+    pub back: u8,
+}
+
+impl QualityTrimmer for Cutadapt {
+    fn trim(&self, qual: &[u8]) -> (usize, usize) {
+        qual_trim(qual, self.front as i32, self.back as i32)
+    }
+}
+
+/// A sliding window as in Trimmomatic: the read is cut at the first
+/// window, from the 5' end, with mean quality below the cutoff, then
+/// bases in that window are kept up to the first one below it. Bases
+/// at the 5' end below the front cutoff are removed first, as with
+/// LEADING in Trimmomatic; a front cutoff of 0 removes none.
+pub struct Window {
+    pub size: usize,
+    pub front: u8,
+    pub cutoff: u8,
+}
+
+impl QualityTrimmer for Window {
+    fn trim(&self, qual: &[u8]) -> (usize, usize) {
+        const QUAL_BASE: usize = 33; // assumes base quality starts at 33
+        let front = self.front as usize + QUAL_BASE;
+        let start = match self.front {
+            0 => 0,
+            _ => qual.iter().take_while(|&&x| (x as usize) < front).count(),
+        };
+        // the window starts after the low quality 5' end
+        match start + self.stop(&qual[start..]) {
+            stop if stop > start => (start, stop),
+            _ => (0, 0),
+        }
+    }
+}
+
+impl Window {
+    /// Where the read ends: at the first window below the cutoff.
+    fn stop(&self, qual: &[u8]) -> usize {
+        const QUAL_BASE: usize = 33; // assumes base quality starts at 33
+        let n = qual.len();
+        if self.size == 0 || n < self.size {
+            return n;
+        }
+        let cutoff = self.cutoff as usize + QUAL_BASE;
+        let min_total = cutoff * self.size;
+        let mut total: usize =
+            qual[..self.size].iter().map(|&x| x as usize).sum();
+        let mut stop = n;
+        for i in 0..=n - self.size {
+            if i > 0 {
+                total += qual[i + self.size - 1] as usize;
+                total -= qual[i - 1] as usize;
+            }
+            if total < min_total {
+                stop = i;
+                break;
+            }
+        }
+        while stop < n && (qual[stop] as usize) >= cutoff {
+            stop += 1;
+        }
+        stop
+    }
+}
+
+/// The modified Mott algorithm, as in phred: each base adds the limit
+/// minus its error probability, and the part of the read with the
+/// highest total is kept.
+pub struct Mott {
+    pub limit: f64,
+}
+
+impl QualityTrimmer for Mott {
+    fn trim(&self, qual: &[u8]) -> (usize, usize) {
+        const QUAL_BASE: u8 = 33; // assumes base quality starts at 33
+        let (mut total, mut best) = (0.0, 0.0);
+        let (mut start, mut best_start, mut best_stop) = (0, 0, 0);
+        for (i, &x) in qual.iter().enumerate() {
+            let q = x.saturating_sub(QUAL_BASE) as f64;
+            total += self.limit - 10f64.powf(-q / 10.0);
+            if total <= 0.0 {
+                total = 0.0;
+                start = i + 1;
+            } else if total > best {
+                best = total;
+                (best_start, best_stop) = (start, i + 1);
+            }
+        }
+        (best_start, best_stop)
+    }
+}
+
+/// Find the positions in the read where quality scores indicate the
+/// read should be trimmed. This is copied from cutadapt source, and
+/// gives the same trim points as its quality_trim_index.
+fn qual_trim(qual: &[u8], cut_front: i32, cut_back: i32) -> (usize, usize) {
+    const QUAL_BASE: i32 = 33; // assumes base quality starts at 33
+
+    /* ADS: COPIED FROM cutadapt SOURCE */
+    let n = qual.len();
+
+    //  find trim position for 5' end
+    let mut start: usize = 0;
+    let mut s: i32 = 0;
+    let mut max_qual: i32 = 0;
+
+    if cut_front > 0 {
+        let cut_front = cut_front + QUAL_BASE;
+        for (i, &q) in qual.iter().enumerate() {
+            s += cut_front - q as i32;
+            if s < 0 {
+                break;
+            }
+            if s > max_qual {
+                max_qual = s;
+                start = i + 1;
+            }
+        }
+    }
+    // same for 3' end
+    let mut stop: usize = n;
+    max_qual = 0;
+    s = 0;
+    let cut_back = cut_back + QUAL_BASE;
+    for (i, &q) in qual.iter().enumerate().rev() {
+        s += cut_back - q as i32;
+        if s < 0 {
+            break;
+        }
+        if s > max_qual {
+            max_qual = s;
+            stop = i;
+        }
+    }
+    if start >= stop {
+        (start, stop) = (0, 0)
+    }
+    (start, stop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cutadapt_gives_cutadapt_trim_points() {
+        // trim points from cutadapt's quality_trim_index
+        let trim = |front, back, qual| Cutadapt { front, back }.trim(qual);
+        assert_eq!(trim(10, 20, b"IIIIIIIIII"), (0, 10));
+        assert_eq!(trim(10, 20, b"##IIIIII##"), (2, 8));
+        assert_eq!(trim(0, 20, b"IIIII5(#"), (0, 6));
+        assert_eq!(trim(15, 20, b"(((IIIII"), (3, 8));
+        assert_eq!(trim(0, 20, b"II+5II##I#"), (0, 9));
+        assert_eq!(trim(10, 0, b"5+IIII"), (0, 6));
+        assert_eq!(trim(10, 20, b"#########"), (0, 0));
+        assert_eq!(trim(10, 20, b""), (0, 0));
+    }
+
+    #[test]
+    fn window_cuts_at_low_quality_window() {
+        let window = Window {
+            size: 4,
+            front: 0,
+            cutoff: 20,
+        };
+        assert_eq!(window.trim(b"IIIIIIII####IIII"), (0, 8));
+        assert_eq!(window.trim(b"III"), (0, 3));
+        assert_eq!(window.trim(b"####"), (0, 0));
+        // the 5' end is cut before the windows start
+        let window = Window {
+            front: 10,
+            ..window
+        };
+        assert_eq!(window.trim(b"##5IIIIIIIIIIIIIII##"), (2, 20));
+        assert_eq!(window.trim(b"####"), (0, 0));
+    }
+
+    #[test]
+    fn mott_keeps_the_best_part() {
+        let mott = Mott { limit: 0.05 };
+        assert_eq!(mott.trim(b"####IIII####"), (4, 8));
+        assert_eq!(mott.trim(b"####"), (0, 0));
+    }
+}
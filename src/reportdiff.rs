@@ -0,0 +1,153 @@
+/* MIT License
+ *
+ * Copyright (c) 2023-2024 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::error::Error;
+
+/// The numbers in a report written with --json, keyed by the position
+/// of the input in the report and the name of the metric. Inputs are
+/// matched by position so reports from runs on files with different
+/// names can still be compared. The pairs, if any, come last.
+struct Report {
+    inputs: Vec<String>,
+    metrics: BTreeMap<(usize, String), f64>,
+}
+
+/// The names of the columns after the first in rows of the arrays of
+/// a report, where the first is the length or size for the row.
+const COLUMNS: [(&str, &[&str]); 2] = [
+    ("lengths", &["reads before", "reads after"]),
+    ("inserts", &["pairs"]),
+];
+
+/// Add each number in `value` to the metrics, named by where it is
+/// in the report, e.g. "bases_trimmed adaptor" or "lengths 30 reads
+/// after".
+fn add_metrics(
+    metrics: &mut BTreeMap<(usize, String), f64>,
+    idx: usize,
+    name: &str,
+    value: &Value,
+) {
+    match value {
+        Value::Number(x) => {
+            if let Some(x) = x.as_f64() {
+                metrics.insert((idx, name.to_string()), x);
+            }
+        }
+        Value::Object(fields) => {
+            for (key, x) in fields {
+                let name = match name.is_empty() {
+                    true => key.clone(),
+                    false => format!("{} {}", name, key),
+                };
+                add_metrics(metrics, idx, &name, x);
+            }
+        }
+        Value::Array(rows) => {
+            let columns = COLUMNS.iter().find(|(x, _)| *x == name);
+            let columns = columns.map_or(&[][..], |(_, x)| *x);
+            for row in rows.iter().filter_map(|x| x.as_array()) {
+                let Some((first, rest)) = row.split_first() else {
+                    continue;
+                };
+                for (column, x) in columns.iter().zip(rest) {
+                    let name = format!("{} {} {}", name, first, column);
+                    add_metrics(metrics, idx, &name, x);
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+fn read_report(filename: &str) -> Result<Report, Box<dyn Error>> {
+    let text = std::fs::read_to_string(filename)?;
+    let not_report =
+        |e: String| format!("not a report from --json: {}: {}", filename, e);
+    let report: Value =
+        serde_json::from_str(&text).map_err(|e| not_report(e.to_string()))?;
+    let inputs = match report.get("inputs").and_then(|x| x.as_array()) {
+        Some(x) => x,
+        None => return Err(not_report("no inputs".to_string()))?,
+    };
+    let mut metrics = BTreeMap::new();
+    let mut names = Vec::new();
+    for (i, input) in inputs.iter().enumerate() {
+        let name = input.get("input").and_then(|x| x.as_str());
+        names.push(name.unwrap_or("-").to_string());
+        add_metrics(&mut metrics, i, "", input);
+    }
+    if let Some(pairs) = report.get("pairs").filter(|x| !x.is_null()) {
+        names.push("pairs".to_string());
+        add_metrics(&mut metrics, inputs.len(), "", pairs);
+    }
+    Ok(Report {
+        inputs: names,
+        metrics,
+    })
+}
+
+/// Compare two reports and print the metrics that changed by at
+/// least the fraction `min_change` of their value in the first, along
+/// with any that are only in one of them.
+pub fn report_diff(
+    a: &str,
+    b: &str,
+    min_change: f64,
+) -> Result<(), Box<dyn Error>> {
+    let Report {
+        inputs,
+        metrics: before,
+    } = read_report(a)?;
+    let after = read_report(b)?.metrics;
+    let input = |i: usize| inputs.get(i).map(|x| x.as_str()).unwrap_or("-");
+    for ((i, key), &x) in &before {
+        match after.get(&(*i, key.clone())) {
+            Some(&y) if x == 0.0 && y != 0.0 => {
+                println!("{}: {}: {} -> {}", input(*i), key, x, y)
+            }
+            Some(&y) => {
+                let change = (y - x) / x.abs();
+                if change.abs() >= min_change && x != y {
+                    println!(
+                        "{}: {}: {} -> {} ({:+.2}%)",
+                        input(*i),
+                        key,
+                        x,
+                        y,
+                        100.0 * change
+                    );
+                }
+            }
+            None => println!("{}: {}: only in {}", input(*i), key, a),
+        }
+    }
+    for (i, key) in after.keys().filter(|x| !before.contains_key(x)) {
+        println!("{}: {}: only in {}", input(*i), key, b);
+    }
+    Ok(())
+}
@@ -0,0 +1,226 @@
+/* MIT License
+ *
+ * Copyright (c) 2023-2024 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+// The parts of htslib that are used here, written in Rust with
+// flate2, for builds without a C toolchain, e.g. static musl or
+// cross builds. Gzip and BGZF are read as one stream of gzip members
+// and BGZF is written one block at a time, with no threads.
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::{DeflateEncoder, GzEncoder};
+use flate2::{Compression, Crc};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// A file to write, where "-" is standard output, as for htslib.
+fn create<P: AsRef<Path>>(path: P) -> std::io::Result<Box<dyn Write>> {
+    Ok(match path.as_ref().to_str() {
+        Some("-") => Box::new(BufWriter::new(std::io::stdout())),
+        _ => Box::new(BufWriter::new(std::fs::File::create(path)?)),
+    })
+}
+
+pub mod tpool {
+    /// No threads are used for compression without htslib, so this
+    /// only stands in for the htslib thread pool.
+    pub struct ThreadPool;
+
+    impl ThreadPool {
+        pub fn new(_n_threads: u32) -> std::io::Result<ThreadPool> {
+            Ok(ThreadPool)
+        }
+    }
+}
+
+pub mod bgzf {
+    use super::*;
+    use crate::BGZF_EOF;
+
+    /// Most bytes in one block, as htslib uses, so even data that
+    /// doesn't compress fits in the 64 KiB a block can have.
+    const BLOCK_SIZE: usize = 0xff00;
+
+    #[derive(Clone, Copy)]
+    pub enum CompressionLevel {
+        Default,
+        Uncompressed,
+    }
+
+    /// Reads gzip, including BGZF, and plain text, known from the
+    /// first two bytes.
+    pub struct Reader(Box<dyn Read>);
+
+    impl Reader {
+        fn new(inner: Box<dyn Read>) -> std::io::Result<Reader> {
+            let mut inner = BufReader::new(inner);
+            Ok(match inner.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+                true => Reader(Box::new(MultiGzDecoder::new(inner))),
+                false => Reader(Box::new(inner)),
+            })
+        }
+        pub fn from_path<P: AsRef<Path>>(path: P) -> std::io::Result<Reader> {
+            Reader::new(Box::new(std::fs::File::open(path)?))
+        }
+        pub fn from_stdin() -> std::io::Result<Reader> {
+            Reader::new(Box::new(std::io::stdin()))
+        }
+        pub fn set_thread_pool(
+            &mut self,
+            _tpool: &super::tpool::ThreadPool,
+        ) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Read for Reader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    /// Writes BGZF: gzip members of at most BLOCK_SIZE bytes each,
+    /// with the size of the member in a header field so readers can
+    /// find the blocks, and an empty block at the end unless asked
+    /// not to.
+    pub struct Writer {
+        inner: Box<dyn Write>,
+        block: Vec<u8>,
+        level: Compression,
+        eof_block: bool,
+    }
+
+    impl Writer {
+        pub fn from_path_with_level<P: AsRef<Path>>(
+            path: P,
+            level: CompressionLevel,
+        ) -> std::io::Result<Writer> {
+            Ok(Writer {
+                inner: create(path)?,
+                block: Vec::with_capacity(BLOCK_SIZE),
+                level: match level {
+                    CompressionLevel::Default => Compression::default(),
+                    CompressionLevel::Uncompressed => Compression::none(),
+                },
+                eof_block: true,
+            })
+        }
+        /// Whether to end with the empty block, which some consumers
+        /// of a stream don't want.
+        pub fn set_eof_block(&mut self, eof_block: bool) {
+            self.eof_block = eof_block;
+        }
+        pub fn set_thread_pool(
+            &mut self,
+            _tpool: &super::tpool::ThreadPool,
+        ) -> std::io::Result<()> {
+            Ok(())
+        }
+        /// Write the last block and the empty block, with an error if
+        /// that fails, which dropping the writer can't give.
+        pub fn finish(mut self) -> std::io::Result<()> {
+            self.write_block()?;
+            if self.eof_block {
+                self.inner.write_all(&BGZF_EOF)?;
+                self.eof_block = false; // not again on drop
+            }
+            self.inner.flush()
+        }
+
+        fn write_block(&mut self) -> std::io::Result<()> {
+            if self.block.is_empty() {
+                return Ok(());
+            }
+            let mut deflate = DeflateEncoder::new(Vec::new(), self.level);
+            deflate.write_all(&self.block)?;
+            let data = deflate.finish()?;
+            let mut crc = Crc::new();
+            crc.update(&self.block);
+            // header with the "BC" field, then data, CRC and size
+            let bsize = (18 + data.len() + 8 - 1) as u16;
+            let mut header = [
+                0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff, 6, 0, b'B', b'C',
+                2, 0, 0, 0,
+            ];
+            header[16..18].copy_from_slice(&bsize.to_le_bytes());
+            self.inner.write_all(&header)?;
+            self.inner.write_all(&data)?;
+            self.inner.write_all(&crc.sum().to_le_bytes())?;
+            self.inner
+                .write_all(&(self.block.len() as u32).to_le_bytes())?;
+            self.block.clear();
+            Ok(())
+        }
+    }
+
+    impl Write for Writer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(BLOCK_SIZE - self.block.len());
+            self.block.extend_from_slice(&buf[..n]);
+            if self.block.len() == BLOCK_SIZE {
+                self.write_block()?;
+            }
+            Ok(n)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.write_block()?;
+            self.inner.flush()
+        }
+    }
+
+    impl Drop for Writer {
+        fn drop(&mut self) {
+            let _ = self.write_block();
+            if self.eof_block {
+                let _ = self.inner.write_all(&BGZF_EOF);
+            }
+            let _ = self.inner.flush();
+        }
+    }
+}
+
+/// One gzip stream, as the htslib "g" mode writes it.
+pub struct GzipWriter(GzEncoder<Box<dyn Write>>);
+
+impl GzipWriter {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Ok(GzipWriter(GzEncoder::new(
+            create(path)?,
+            Compression::default(),
+        )))
+    }
+    /// End the gzip stream, with an error if that fails.
+    pub fn finish(self) -> std::io::Result<()> {
+        self.0.finish()?.flush()
+    }
+}
+
+impl Write for GzipWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
@@ -0,0 +1,137 @@
+/* MIT License
+ *
+ * Copyright (c) 2023-2024 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use std::collections::HashSet;
+use std::error::Error;
+
+const K: usize = 21; // k-mer size
+const W: usize = 11; // number of k-mers in a minimizer window
+const MIN_HITS: usize = 2; // shared minimizers to assign a read
+
+/// Mix the bits of a k-mer so minimizers are not biased to poly-A.
+/// This is the finalizer from splitmix64.
+fn hash(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// The minimizers of a sequence: for each window of W consecutive
+/// k-mers, the smallest hash of a canonical k-mer. Any k-mer with a
+/// base other than ACGT is skipped.
+fn minimizers(seq: &[u8]) -> Vec<u64> {
+    let mask = (1u64 << (2 * K)) - 1;
+    let mut hashes: Vec<u64> = Vec::with_capacity(seq.len());
+    let (mut fwd, mut rev, mut valid) = (0u64, 0u64, 0usize);
+    for &c in seq {
+        let code = match c {
+            b'A' | b'a' => 0,
+            b'C' | b'c' => 1,
+            b'G' | b'g' => 2,
+            b'T' | b't' => 3,
+            _ => {
+                valid = 0;
+                continue;
+            }
+        };
+        fwd = ((fwd << 2) | code) & mask;
+        rev = (rev >> 2) | ((3 - code) << (2 * (K - 1)));
+        valid += 1;
+        if valid >= K {
+            hashes.push(hash(fwd.min(rev)));
+        }
+    }
+    let mut mins: Vec<u64> = Vec::new();
+    for window in hashes.windows(W.min(hashes.len().max(1))) {
+        if let Some(&m) = window.iter().min() {
+            if mins.last() != Some(&m) {
+                mins.push(m);
+            }
+        }
+    }
+    mins
+}
+
+/// Screen assigns reads to the reference that shares the most
+/// minimizers with the read, giving a rough picture of where the
+/// reads come from (e.g. host, microbe or PhiX). The references
+/// should be small, since all their minimizers are kept in memory.
+pub struct Screen {
+    pub names: Vec<String>,
+    pub n_reads: usize, // number of reads to screen
+    sketches: Vec<HashSet<u64>>,
+}
+
+impl Screen {
+    /// Build the sketches from `name=file` pairs where each file is
+    /// in FASTA format.
+    pub fn new(
+        refs: &[String],
+        n_reads: usize,
+    ) -> Result<Screen, Box<dyn Error>> {
+        let mut names = Vec::new();
+        let mut sketches = Vec::new();
+        for r in refs {
+            let (name, filename) = match r.split_once('=') {
+                Some(x) => x,
+                None => return Err(format!("expected name=file: {}", r))?,
+            };
+            let text = std::fs::read(filename)?;
+            let mut sketch = HashSet::new();
+            // each FASTA record separately, so k-mers do not span two
+            for rec in text.split(|&x| x == b'>').filter(|x| !x.is_empty()) {
+                let seq: Vec<u8> = rec
+                    .split(|&x| x == b'\n')
+                    .skip(1)
+                    .flatten()
+                    .filter(|x| !x.is_ascii_whitespace())
+                    .copied()
+                    .collect();
+                sketch.extend(minimizers(&seq));
+            }
+            names.push(name.to_string());
+            sketches.push(sketch);
+        }
+        Ok(Screen {
+            names,
+            n_reads,
+            sketches,
+        })
+    }
+
+    /// The index of the reference with the most minimizers in common
+    /// with the read, if any has enough.
+    pub fn classify(&self, read: &[u8]) -> Option<usize> {
+        let mins = minimizers(read);
+        self.sketches
+            .iter()
+            .map(|s| mins.iter().filter(|x| s.contains(x)).count())
+            .enumerate()
+            .filter(|&(_, hits)| hits >= MIN_HITS)
+            .max_by_key(|&(_, hits)| hits)
+            .map(|(i, _)| i)
+    }
+}
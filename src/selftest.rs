@@ -0,0 +1,197 @@
+/* MIT License
+ *
+ * Copyright (c) 2023-2024 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use crate::{remove_adaptors, remove_adaptors_paired, Cutadapt, Params};
+use crate::bgzf;
+use std::error::Error;
+use std::io::Read;
+
+const SINGLE: &[u8] = include_bytes!("selftest/single.fq");
+const SINGLE_GZ: &[u8] = include_bytes!("selftest/single.fq.gz");
+const SINGLE_BGZ: &[u8] = include_bytes!("selftest/single.fq.bgz");
+const R1: &[u8] = include_bytes!("selftest/r1.fq");
+const R2: &[u8] = include_bytes!("selftest/r2.fq");
+const MALFORMED: &[u8] = include_bytes!("selftest/malformed.fq");
+const QUAL: &[u8] = include_bytes!("selftest/qual.fq");
+
+/// A case to run: a name, the input file name and contents, those of
+/// the second end for paired reads, the buffer size, the 5' and 3'
+/// quality score cutoffs and the checksum of the uncompressed output,
+/// or None if the input should be rejected.
+struct Case {
+    name: &'static str,
+    filename: &'static str,
+    data: &'static [u8],
+    mate: Option<(&'static str, &'static [u8])>,
+    buf_sz: usize,
+    cutoffs: (u8, u8),
+    checksum: Option<u64>,
+}
+
+const CASES: &[Case] = &[
+    Case {
+        name: "single-end",
+        filename: "single.fq",
+        data: SINGLE,
+        mate: None,
+        buf_sz: 256 * 1024,
+        cutoffs: (0, 20),
+        checksum: Some(0x5ff4a4648a688587),
+    },
+    Case {
+        name: "single-end with small buffer",
+        filename: "single.fq",
+        data: SINGLE,
+        mate: None,
+        buf_sz: 64,
+        cutoffs: (0, 20),
+        checksum: Some(0x5ff4a4648a688587),
+    },
+    Case {
+        name: "paired-end",
+        filename: "r1.fq",
+        data: R1,
+        mate: Some(("r2.fq", R2)),
+        buf_sz: 256 * 1024,
+        cutoffs: (0, 20),
+        checksum: Some(0x16729485796a6e90),
+    },
+    Case {
+        name: "paired-end with unmatched names",
+        filename: "r1.fq",
+        data: R1,
+        mate: Some(("single.fq", SINGLE)),
+        buf_sz: 256 * 1024,
+        cutoffs: (0, 20),
+        checksum: None,
+    },
+    Case {
+        name: "gzip input",
+        filename: "single.fq.gz",
+        data: SINGLE_GZ,
+        mate: None,
+        buf_sz: 256 * 1024,
+        cutoffs: (0, 20),
+        checksum: Some(0x5ff4a4648a688587),
+    },
+    Case {
+        name: "bgzf input",
+        filename: "single.fq.bgz",
+        data: SINGLE_BGZ,
+        mate: None,
+        buf_sz: 256 * 1024,
+        cutoffs: (0, 20),
+        checksum: Some(0x5ff4a4648a688587),
+    },
+    Case {
+        name: "malformed input",
+        filename: "malformed.fq",
+        data: MALFORMED,
+        mate: None,
+        buf_sz: 256 * 1024,
+        cutoffs: (0, 20),
+        checksum: None,
+    },
+    // the same trim points as cutadapt with -q 10,20
+    Case {
+        name: "quality trimming",
+        filename: "qual.fq",
+        data: QUAL,
+        mate: None,
+        buf_sz: 256 * 1024,
+        cutoffs: (10, 20),
+        checksum: Some(0xe440c46eb9aed017),
+    },
+];
+
+/// FNV-1a hash, which is enough to tell if the output changed.
+fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |h, &x| {
+        (h ^ x as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Run one case in the directory `dir` and return the checksum of
+/// the output, with the second end after the first for paired reads.
+fn run_case(case: &Case, dir: &std::path::Path) -> Result<u64, Box<dyn Error>> {
+    let input = dir.join(case.filename).to_string_lossy().to_string();
+    let output = dir.join("out.fq.gz").to_string_lossy().to_string();
+    std::fs::write(&input, case.data)?;
+    let params = Params {
+        zip: true,
+        buf_sz: case.buf_sz,
+        qual_trimmer: Box::new(Cutadapt {
+            front: case.cutoffs.0,
+            back: case.cutoffs.1,
+        }),
+        ..Default::default()
+    };
+    let mut out = Vec::new();
+    match case.mate {
+        Some((filename, data)) => {
+            let input2 = dir.join(filename).to_string_lossy().to_string();
+            let output2 = dir.join("out2.fq.gz").to_string_lossy().to_string();
+            std::fs::write(&input2, data)?;
+            remove_adaptors_paired(
+                &params,
+                (&input, &input2),
+                (&output, &output2),
+                None,
+                (None, None),
+            )?;
+            bgzf::Reader::from_path(&output)?.read_to_end(&mut out)?;
+            bgzf::Reader::from_path(&output2)?.read_to_end(&mut out)?;
+        }
+        None => {
+            remove_adaptors(&params, &input, &output, None)?;
+            bgzf::Reader::from_path(&output)?.read_to_end(&mut out)?;
+        }
+    }
+    Ok(checksum(&out))
+}
+
+/// Run the whole program on small examples built into the binary and
+/// check that the output is as expected, so an installation can be
+/// tested without any other files.
+pub fn self_test() -> Result<(), Box<dyn Error>> {
+    let dir = std::env::temp_dir()
+        .join(format!("adapto-rs-self-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let mut failed = 0;
+    for case in CASES {
+        let ok = match (run_case(case, &dir), case.checksum) {
+            (Ok(x), Some(y)) => x == y,
+            (Err(_), None) => true,
+            _ => false,
+        };
+        eprintln!("{}: {}", case.name, if ok { "ok" } else { "FAILED" });
+        failed += !ok as usize;
+    }
+    std::fs::remove_dir_all(&dir)?;
+    match failed {
+        0 => Ok(()),
+        _ => Err(format!("self-test failed: {} of {}", failed, CASES.len()))?,
+    }
+}
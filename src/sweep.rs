@@ -0,0 +1,119 @@
+/* MIT License
+ *
+ * Copyright (c) 2023-2024 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use crate::{open_input, Adaptor, FQInput, Params, Stringency};
+use std::error::Error;
+use std::io::Read;
+
+/// Reads trimmed and bases trimmed for one stringency.
+type Counts = (usize, usize);
+
+/// For each stringency in `grid`, the number of the first `n_reads`
+/// reads that a 3' adaptor would be trimmed from and the bases that
+/// would be trimmed, as the adaptors are searched for in trimming.
+pub(crate) fn sweep_counts<R: Read>(
+    reader: &mut R,
+    adaptors: &[Vec<u8>],
+    max_errors: usize,
+    grid: &[Stringency],
+    n_reads: usize,
+) -> Result<(usize, Vec<Counts>), Box<dyn Error>> {
+    let matchers: Vec<Vec<Adaptor>> = grid
+        .iter()
+        .map(|&stringency| {
+            adaptors
+                .iter()
+                .map(|x| Adaptor {
+                    max_err: max_errors,
+                    stringency,
+                    ..Adaptor::new(x)
+                })
+                .collect()
+        })
+        .collect();
+    let params = Params::default();
+    let mut input = FQInput::new(reader, &params);
+    let mut counts = vec![(0, 0); grid.len()];
+    let mut reads = 0;
+    while reads < n_reads {
+        input.fill()?;
+        input.truncate(n_reads - reads);
+        for x in &input.recs {
+            let read = &input.buf[x.r..x.r + x.stop];
+            for (back, count) in matchers.iter().zip(counts.iter_mut()) {
+                let m = read.len();
+                let i = back.iter().map(|a| a.find(read, m)).min();
+                let i = i.unwrap_or(m);
+                count.0 += (i < m) as usize;
+                count.1 += m - i;
+            }
+        }
+        reads += input.recs.len();
+        if input.eof {
+            break;
+        }
+    }
+    Ok((reads, counts))
+}
+
+/// Run the 3' adaptor search, and nothing else, over the first
+/// `n_reads` reads of the input for each combination of minimum
+/// overlap and match fraction, and print the fraction of reads that
+/// would be trimmed for each, to choose a stringency from the data.
+/// No reads are written.
+pub fn sweep(
+    input: &str,
+    adaptors: &[Vec<u8>],
+    max_errors: usize,
+    overlaps: &[usize],
+    fracs: &[f64],
+    n_reads: usize,
+) -> Result<(), Box<dyn Error>> {
+    let grid: Vec<Stringency> = overlaps
+        .iter()
+        .flat_map(|&min_overlap| {
+            fracs
+                .iter()
+                .map(move |&frac| Stringency { min_overlap, frac })
+        })
+        .collect();
+    let mut reader = open_input(input)?;
+    let (reads, counts) =
+        sweep_counts(&mut reader, adaptors, max_errors, &grid, n_reads)?;
+    let rate = |x: usize| x as f64 / std::cmp::max(reads, 1) as f64;
+    println!("reads: {}", reads);
+    println!("min_overlap\tfrac\ttrimmed\trate\tbases_per_read");
+    for (s, (trimmed, bases)) in grid.iter().zip(counts) {
+        println!(
+            "{}\t{}\t{}\t{:.4}\t{:.2}",
+            s.min_overlap,
+            s.frac,
+            trimmed,
+            rate(trimmed),
+            rate(bases)
+        );
+    }
+    Ok(())
+}